@@ -0,0 +1,6 @@
+use std::env;
+
+fn main() {
+    // the target the tests are built for, used to find cargo's runner for it
+    println!("cargo:rustc-env=SECOND_LAW_TARGET={}", env::var("TARGET").unwrap());
+}
@@ -0,0 +1,329 @@
+#![cfg(unix)]
+
+extern crate second_law;
+
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use second_law::{Batch, FdSource, Pipeline, Readiness, Scene, Stream, UCommand};
+
+fn scene() -> Scene {
+    Scene::new("/bin/sh")
+}
+
+// whether the process exists and hasn't exited. an orphan killed here may linger as a zombie
+// where nothing reaps orphans, e.g. in a container whose init doesn't
+fn is_running(pid: u32) -> bool {
+    match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => stat.rsplit(')').next().map(|rest| !rest.trim_start().starts_with('Z')).unwrap_or(false),
+        Err(_) => Command::new("kill").arg("-0").arg(pid.to_string()).status().map(|s| s.success()).unwrap_or(false),
+    }
+}
+
+#[test]
+fn timeout_kills_backgrounded_grandchild() {
+    let started = Instant::now();
+    let result = scene().ucmd().arg("-c").arg("sleep 30 & echo $!; wait")
+                        .timeout(Duration::from_millis(500)).run();
+    assert!(result.timed_out);
+    assert!(started.elapsed() < Duration::from_secs(10), "took {:?}", started.elapsed());
+    let grandchild: u32 = result.stdout.trim().parse().unwrap();
+    // SIGKILL is delivered asynchronously
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while is_running(grandchild) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!is_running(grandchild), "grandchild {} survived the timeout", grandchild);
+}
+
+#[test]
+fn large_input_through_cat() {
+    let input: Vec<u8> = (0..5 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let result = scene().cmd("cat").pipe_in(input.clone()).timeout(Duration::from_secs(30)).succeeds();
+    result.stdout_is_bytes(&input);
+}
+
+// pseudo-terminal handles among the descriptors of the process other than its stdio
+#[cfg(target_os = "linux")]
+fn inherited_terminals(pid: u32) -> Vec<String> {
+    fs::read_dir(format!("/proc/{}/fd", pid)).map(|fds| {
+        fds.filter_map(|fd| fd.ok())
+           .filter(|fd| fd.file_name().to_string_lossy().parse::<u32>().map(|n| n > 2).unwrap_or(false))
+           .filter_map(|fd| fs::read_link(fd.path()).ok())
+           .map(|target| target.to_string_lossy().into_owned())
+           .filter(|target| target.starts_with("/dev/pts/") || target.ends_with("ptmx"))
+           .collect()
+    }).unwrap_or_default()
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn pty_not_inherited_by_concurrent_spawns() {
+    let stop = Arc::new(AtomicBool::new(false));
+    let runner = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let mut s = scene();
+            while !stop.load(Ordering::SeqCst) {
+                s.ucmd().arg("-c").arg("echo x").pty().timeout(Duration::from_secs(10)).succeeds().stdout_is("x");
+            }
+        })
+    };
+    let mut inherited = Vec::new();
+    for _ in 0..200 {
+        let mut sibling = Command::new("sleep").arg("5").spawn().unwrap();
+        inherited.extend(inherited_terminals(sibling.id()));
+        let _ = sibling.kill();
+        let _ = sibling.wait();
+    }
+    stop.store(true, Ordering::SeqCst);
+    runner.join().unwrap();
+    assert!(inherited.is_empty(), "concurrently spawned processes inherited {:?}", inherited);
+}
+
+#[test]
+fn pty_command_closing_its_stdio() {
+    scene().cmd("cat").pty().pipe_in("abc\n").timeout(Duration::from_secs(10)).succeeds();
+}
+
+#[test]
+fn capture_extra_fd() {
+    let result = scene().ucmd().arg("-c").arg("echo out; echo extra >&3")
+                        .pass_fd(3, FdSource::Capture).succeeds();
+    result.stdout_is("out").fd_is(3, "extra");
+}
+
+#[test]
+fn leftover_holding_stdout() {
+    let started = Instant::now();
+    let result = scene().ucmd().arg("-c").arg("sleep 4 & echo done")
+                        .track_leftover_processes().run();
+    assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+    assert!(!result.timed_out);
+    assert_eq!(result.stdout, "done\n");
+    let leftover = result.leftover_processes.clone().unwrap();
+    assert_eq!(leftover.len(), 1, "{:?}", leftover);
+    assert_eq!(leftover[0].command, "sleep");
+    Command::new("kill").arg(leftover[0].pid.to_string()).status().unwrap();
+}
+
+#[test]
+fn service_ready_on_output_then_stopped() {
+    let mut s = scene();
+    let mut ucmd = s.ucmd();
+    ucmd.arg("-c").arg("trap 'echo bye; exit 0' TERM; echo ready; while :; do sleep 0.05; done");
+    let mut service = s.spawn_service(ucmd, Readiness::Output(Stream::Stdout, String::from("^ready")));
+    let result = service.stop();
+    assert!(!result.timed_out);
+    assert_eq!(result.stdout, "ready\nbye\n");
+}
+
+#[test]
+fn output_redirected_within_scene() {
+    let mut s = scene();
+    s.ucmd().arg("-c").arg("echo out; echo err >&2; echo extra >&3")
+     .stdout_to_file("out").stderr_to_file("err")
+     .pass_fd(3, FdSource::WriteFile(PathBuf::from("extra"))).succeeds();
+    let at = s.working_dir();
+    assert_eq!(at.read("out"), "out\n");
+    assert_eq!(at.read("err"), "err\n");
+    assert_eq!(at.read("extra"), "extra\n");
+}
+
+#[test]
+fn output_paths_outside_scene_rejected() {
+    let outside = env::temp_dir().join("second_law-outside-scene");
+    let mut s = scene();
+    symlink(env::temp_dir(), s.working_dir().plus("link")).unwrap();
+    let escaping = [outside.clone(), PathBuf::from("../second_law-outside-scene"),
+                    PathBuf::from("link/second_law-outside-scene"), PathBuf::from("")];
+    for path in escaping.iter() {
+        let stdout = panic::catch_unwind(AssertUnwindSafe(|| { s.ucmd().stdout_to_file(path); }));
+        assert!(stdout.is_err(), "stdout_to_file({:?}) was accepted", path);
+        let stderr = panic::catch_unwind(AssertUnwindSafe(|| { s.ucmd().stderr_to_file(path); }));
+        assert!(stderr.is_err(), "stderr_to_file({:?}) was accepted", path);
+        let fd = panic::catch_unwind(AssertUnwindSafe(|| { s.ucmd().pass_fd(3, FdSource::WriteFile(path.clone())); }));
+        assert!(fd.is_err(), "pass_fd(3, {:?}) was accepted", path);
+    }
+    assert!(!outside.exists());
+}
+
+#[test]
+fn exit_code_and_signal() {
+    scene().ucmd().arg("-c").arg("exit 3").fails().code_is(3).code_in(&[1, 3]).not_signaled();
+    let result = scene().ucmd().arg("-c").arg("kill -TERM $$").fails();
+    result.signaled_with(15);
+    assert_eq!(result.code(), None);
+}
+
+#[test]
+fn binary_output_captured_exactly() {
+    let result = scene().ucmd().arg("-c").arg("printf 'a\\377\\000b'; printf '\\376' >&2").succeeds();
+    result.stdout_is_bytes(b"a\xff\x00b").stderr_is_bytes(b"\xfe");
+    assert_eq!(result.stdout, "a\u{fffd}\u{0}b");
+    assert_eq!(result.stderr_lossy(), "\u{fffd}");
+}
+
+#[test]
+fn interactive_session_exchanges_lines() {
+    let mut session = scene().ucmd().arg("-c").arg("while read line; do echo \"got $line\"; done; echo bye")
+                             .run_interactive();
+    session.send_line("one").expect_line("got one");
+    session.send_line("two").expect_line("got two");
+    session.close_stdin().expect_line("bye");
+    session.wait().success();
+}
+
+#[test]
+fn pipeline_streams_between_stages() {
+    let mut s = scene();
+    let mut produce = s.ucmd();
+    produce.arg("-c").arg("printf 'b\\na\\nc\\n'");
+    let mut sort = s.ucmd();
+    sort.arg("-c").arg("sort");
+    Pipeline::new(vec![produce, sort]).succeeds().last().stdout_is("a\nb\nc");
+
+    // the first stage gets SIGPIPE once the second stops reading
+    let mut endless = s.ucmd();
+    endless.arg("-c").arg("exec yes");
+    let mut head = s.ucmd();
+    head.arg("-c").arg("head -n 1");
+    let result = Pipeline::new(vec![endless, head]).run();
+    result.stage(0).signaled_with(13);
+    result.last().stdout_is("y");
+}
+
+#[test]
+fn early_closed_stdout_breaks_the_pipe() {
+    let result = scene().ucmd().arg("-c").arg("exec yes").stdout_read_limit(10).timeout(Duration::from_secs(10)).run();
+    result.broken_pipe_quietly().signaled_with(13);
+    assert!(result.stdout.starts_with("y\ny\n"), "{:?}", result.stdout);
+}
+
+#[test]
+fn signal_delivered_to_running_command() {
+    let mut session = scene().ucmd().arg("-c").arg("trap 'echo terminated; exit 0' TERM; echo ready; while :; do sleep 0.05; done")
+                             .run_interactive();
+    session.expect_line("ready");
+    session.signal(15);
+    let result = session.wait();
+    result.success();
+    assert!(result.stdout.ends_with("terminated\n"), "{:?}", result.stdout);
+
+    let mut session = scene().ucmd().arg("-c").arg("echo ready; exec sleep 10").run_interactive();
+    session.expect_line("ready");
+    session.signal_after(2, Duration::from_millis(100));
+    session.wait().signaled_with(2);
+}
+
+#[test]
+fn resource_limits_applied() {
+    scene().ucmd().arg("-c").arg("ulimit -n; ulimit -f")
+           .limit_open_files(17).limit_file_size(512 * 1024).succeeds().stdout_is("17\n1024");
+    // writing past the file size limit is refused
+    scene().ucmd().arg("-c").arg("head -c 2048 /dev/zero > big").limit_file_size(1024).fails();
+}
+
+#[test]
+fn resource_usage_measured() {
+    let result = scene().ucmd().arg("-c").arg("i=0; while [ $i -lt 100000 ]; do i=$((i+1)); done; sleep 0.2").succeeds();
+    assert!(result.wall_time >= Duration::from_millis(200), "{:?}", result.wall_time);
+    result.completes_within(Duration::from_secs(30)).max_rss_below(1 << 32);
+    let usage = result.usage.unwrap();
+    assert!(usage.user_time + usage.system_time > Duration::from_millis(0));
+    assert!(usage.max_rss > 0);
+}
+
+#[test]
+fn environment_controlled() {
+    let mut s = scene();
+    s.env("FROM_SCENE", "scene");
+    s.env("REMOVED", "removed");
+    s.ucmd().env("FROM_CMD", "cmd").env_remove("REMOVED")
+     .arg("-c").arg("echo \"$FROM_SCENE $FROM_CMD ${REMOVED-unset} ${CARGO_MANIFEST_DIR-unset}\"")
+     .succeeds().stdout_is("scene cmd unset unset");
+    let mut ucmd = s.ucmd();
+    ucmd.inherit_env(&["CARGO_MANIFEST_DIR"]);
+    assert_eq!(ucmd.effective_env().get(OsStr::new("CARGO_MANIFEST_DIR")), env::var_os("CARGO_MANIFEST_DIR").as_ref());
+    s.ucmd_keepenv().arg("-c").arg("echo \"${CARGO_MANIFEST_DIR+set}\"").succeeds().stdout_is("set");
+}
+
+#[test]
+fn command_run_from_scene_subdirectory() {
+    let mut s = scene();
+    s.working_dir().mkdir("sub");
+    s.working_dir().write("sub/file", "in sub");
+    s.ucmd_in("sub").arg("-c").arg("cat file").succeeds().stdout_is("in sub");
+    for subdir in ["missing", "..", "sub/file"].iter() {
+        let rejected = panic::catch_unwind(AssertUnwindSafe(|| { s.ucmd().current_dir_rel(subdir); }));
+        assert!(rejected.is_err(), "current_dir_rel({:?}) was accepted", subdir);
+    }
+}
+
+#[test]
+fn stdin_modes() {
+    scene().ucmd().arg("-c").arg("cat").stdin_null().succeeds().no_stdout();
+    scene().ucmd().arg("-c").arg("cat").stdin_closed().fails();
+    let mut s = scene();
+    s.working_dir().write("input", "from a file\n");
+    s.ucmd().arg("-c").arg("cat").stdin_file("input").succeeds().stdout_is("from a file");
+}
+
+#[test]
+fn combined_output_in_order() {
+    let result = scene().ucmd().arg("-c").arg("echo first; sleep 0.1; echo second >&2; sleep 0.1; echo third")
+                        .capture_combined().succeeds();
+    result.combined_output_is("first\nsecond\nthird")
+          .output_order((Stream::Stdout, "first"), (Stream::Stderr, "second"))
+          .output_order((Stream::Stderr, "second"), (Stream::Stdout, "third"));
+    result.stdout_is("first\nthird").stderr_is("second");
+}
+
+#[test]
+fn runner_prefixes_the_binary() {
+    let mut s = scene();
+    s.runner(&["/usr/bin/env", "WRAPPED=by the runner"]);
+    s.ucmd().arg("-c").arg("echo \"$WRAPPED\"").succeeds().stdout_is("by the runner");
+    let mut direct = scene();
+    direct.runner::<&str>(&[]);
+    direct.ucmd().arg("-c").arg("echo \"${WRAPPED-direct}\"").succeeds().stdout_is("direct");
+}
+
+#[test]
+fn batch_runs_commands_in_parallel() {
+    let mut s = scene();
+    let commands: Vec<UCommand> = (0..4).map(|i| {
+        let mut ucmd = s.ucmd();
+        ucmd.arg("-c").arg(format!("sleep 0.5; echo {}", i));
+        ucmd
+    }).collect();
+    let started = Instant::now();
+    let result = Batch::new(commands).workers(4).succeeds();
+    assert!(started.elapsed() < Duration::from_millis(1900), "took {:?}", started.elapsed());
+    result.check_each(|i, result| { result.stdout_is(i.to_string()); });
+}
+
+#[cfg(feature = "http-stub")]
+#[test]
+fn http_stub_answers_commands() {
+    let mut s = Scene::new("/bin/bash");
+    s.serve_http_stub();
+    s.http_stub().route("GET", "/greeting", 200, "hello from the stub");
+    let request = "url={http_stub}; address=${url#http://}; exec 3<>/dev/tcp/${address%:*}/${address##*:}; \
+                   printf 'GET /greeting?name=x HTTP/1.0\\r\\nX-Test: yes\\r\\n\\r\\n' >&3; cat <&3";
+    let result = s.ucmd().arg("-c").arg(request).succeeds();
+    assert!(result.stdout.starts_with("HTTP/1.1 200"), "{}", result.stdout);
+    assert!(result.stdout.ends_with("\r\n\r\nhello from the stub"), "{}", result.stdout);
+    let stub = s.http_stub();
+    stub.received_times("GET", "/greeting", 1);
+    assert_eq!(stub.requests()[0].header("x-test"), Some("yes"));
+}
@@ -0,0 +1,29 @@
+#![cfg(unix)]
+
+extern crate second_law;
+
+use std::env;
+
+use second_law::Scene;
+
+// the only test in this file, as it sets variables for the whole test process
+#[test]
+fn instrumentation_env_forwarded() {
+    env::set_var("LLVM_PROFILE_FILE", "cov.profraw");
+    env::set_var("ASAN_OPTIONS", "detect_leaks=0");
+    let script = "echo \"$LLVM_PROFILE_FILE\"; echo \"$ASAN_OPTIONS\"";
+
+    let outputs: Vec<String> = (0..2).map(|_| Scene::new("/bin/sh").ucmd().arg("-c").arg(script).succeeds().stdout).collect();
+    for output in outputs.iter() {
+        let mut lines = output.lines();
+        let profile = lines.next().unwrap();
+        assert!(profile.starts_with(env::current_dir().unwrap().to_str().unwrap()), "{}", profile);
+        assert!(profile.ends_with("-%p.profraw"), "{}", profile);
+        assert_eq!(lines.next(), Some("detect_leaks=0"));
+    }
+    assert!(outputs[0] != outputs[1], "commands share the profile file {}", outputs[0]);
+
+    let mut s = Scene::new("/bin/sh");
+    s.forward_instrumentation_env(false);
+    s.ucmd().arg("-c").arg(script).succeeds().stdout_is("\n");
+}
@@ -0,0 +1,24 @@
+#![cfg(unix)]
+
+extern crate second_law;
+extern crate tempdir;
+
+use std::fs;
+
+use second_law::Scene;
+use tempdir::TempDir;
+
+// a scene for a copy of /bin/sh whose name has an extension, as the test binaries cargo builds do
+fn scene_with_extension(dir: &TempDir) -> Scene {
+    let bin = dir.path().join("shell.test");
+    fs::copy("/bin/sh", &bin).unwrap();
+    Scene::new(bin)
+}
+
+#[test]
+fn argv0_keeps_name_without_exe_extension() {
+    let dir = TempDir::new("second_law-multicall").unwrap();
+    let mut s = scene_with_extension(&dir);
+    s.multicall_argv0("echo");
+    s.ucmd().arg("-c").arg("basename \"$0\"").succeeds().stdout_is("echo");
+}
@@ -0,0 +1,37 @@
+#![cfg(unix)]
+
+extern crate second_law;
+extern crate tempdir;
+
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+use second_law::Scene;
+use tempdir::TempDir;
+
+// a directory whose name isn't valid unicode
+fn non_utf8_dir(parent: &TempDir) -> PathBuf {
+    let dir = parent.path().join(OsStr::from_bytes(b"non-utf8-\xff"));
+    fs::create_dir(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn binary_in_non_utf8_dir() {
+    let parent = TempDir::new("second_law-paths").unwrap();
+    let bin = non_utf8_dir(&parent).join("sh");
+    fs::copy("/bin/sh", &bin).unwrap();
+    Scene::new(&bin).ucmd().arg("-c").arg("echo ok").succeeds().stdout_is("ok");
+}
+
+#[test]
+fn fixtures_in_non_utf8_dir() {
+    let parent = TempDir::new("second_law-paths").unwrap();
+    let fixtures = non_utf8_dir(&parent);
+    fs::write(fixtures.join("input"), "from the fixtures\n").unwrap();
+    let mut s = Scene::new("/bin/sh");
+    s.fixtures_root(&fixtures);
+    s.ucmd().arg("-c").arg("cat input").succeeds().stdout_is("from the fixtures");
+}
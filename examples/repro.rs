@@ -0,0 +1,114 @@
+#![cfg(unix)]
+
+extern crate second_law;
+extern crate tempdir;
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::panic;
+use std::path::PathBuf;
+use std::process::Command;
+
+use second_law::{Pipeline, Scene};
+use tempdir::TempDir;
+
+fn scene() -> Scene {
+    Scene::new("/bin/sh")
+}
+
+// the directory of the repro script written for the command containing the marker, if any
+fn repro_dir(marker: &str) -> Option<PathBuf> {
+    fs::read_dir(env::temp_dir()).unwrap()
+       .filter_map(|entry| entry.ok())
+       .filter(|entry| entry.file_name().to_string_lossy().starts_with("second_law-repro"))
+       .map(|entry| entry.path())
+       .find(|dir| fs::read_to_string(dir.join("repro.sh")).map(|script| script.contains(marker)).unwrap_or(false))
+}
+
+#[test]
+fn fields_move_out_of_result() {
+    let stdout = scene().ucmd().arg("-c").arg("echo moved").run().stdout;
+    assert_eq!(stdout, "moved\n");
+}
+
+#[test]
+fn repro_written_on_failed_assertion() {
+    let marker = "repro_written_on_failed_assertion";
+    let failed = panic::catch_unwind(|| {
+        scene().ucmd().arg("-c").arg(format!("echo {}", marker)).run().stdout_is("something else");
+    });
+    assert!(failed.is_err());
+    let dir = repro_dir(marker).expect("no repro script was written");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn no_repro_without_failure() {
+    let marker = "no_repro_without_failure";
+    scene().ucmd().arg("-c").arg(format!("echo {}", marker)).run().stdout_is(marker);
+    assert_eq!(repro_dir(marker), None);
+}
+
+#[test]
+fn repro_copies_links_as_is() {
+    let marker = "repro_copies_links_as_is";
+    let failed = panic::catch_unwind(|| {
+        let mut s = scene();
+        {
+            let at = s.working_dir();
+            at.symlink("missing", "dangling");
+            at.touch("file");
+            symlink("loop_b", at.plus_as_string("loop_a")).unwrap();
+            symlink("loop_a", at.plus_as_string("loop_b")).unwrap();
+            symlink("/", at.plus_as_string("root")).unwrap();
+        }
+        s.ucmd().arg("-c").arg(format!("echo {}", marker)).run().stdout_is("something else");
+    });
+    assert!(failed.is_err());
+    let dir = repro_dir(marker).expect("no repro script was written");
+    let copy = dir.join("scene");
+    assert!(copy.join("file").is_file());
+    assert!(fs::read_link(copy.join("dangling")).unwrap().ends_with("missing"));
+    assert_eq!(fs::read_link(copy.join("loop_a")).unwrap(), PathBuf::from("loop_b"));
+    assert_eq!(fs::read_link(copy.join("loop_b")).unwrap(), PathBuf::from("loop_a"));
+    assert_eq!(fs::read_link(copy.join("root")).unwrap(), PathBuf::from("/"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn pipeline_repro_runs_every_stage() {
+    let marker = "pipeline_repro_runs_every_stage";
+    let failed = panic::catch_unwind(|| {
+        let mut s = scene();
+        let mut first = s.ucmd();
+        first.arg("-c").arg("cat; echo b");
+        first.pipe_in("a\n");
+        let mut last = s.ucmd();
+        last.arg("-c").arg(format!("tr ab AB # {}", marker));
+        Pipeline::new(vec![first, last]).run().last().stdout_is("something else");
+    });
+    assert!(failed.is_err());
+    let dir = repro_dir(marker).expect("no repro script was written");
+    let rerun = Command::new(dir.join("repro.sh")).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&rerun.stdout), "A\nB\n");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn multicall_repro_relinks_under_the_same_name() {
+    let marker = "multicall_repro_relinks_under_the_same_name";
+    let bin_dir = TempDir::new("second_law-multicall").unwrap();
+    let bin = bin_dir.path().join("shell.test");
+    fs::copy("/bin/sh", &bin).unwrap();
+    let failed = panic::catch_unwind(|| {
+        let mut s = Scene::new(&bin);
+        s.multicall_argv0("echo");
+        s.ucmd().arg("-c").arg(format!("basename \"$0\" # {}", marker)).run().stdout_is("something else");
+    });
+    assert!(failed.is_err());
+    let dir = repro_dir(marker).expect("no repro script was written");
+    let rerun = Command::new(dir.join("repro.sh")).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&rerun.stdout), "echo\n");
+    fs::remove_dir_all(dir).unwrap();
+}
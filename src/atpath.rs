@@ -11,7 +11,7 @@ use std::os::unix::fs::symlink as symlink_file;
 #[cfg(windows)]
 use std::os::windows::fs::symlink_file;
 
-use super::common::log_info;
+use super::common::{log_info, os_display};
 use super::settings::SceneSettings;
 
 /// Object-oriented path struct that represents and operates on
@@ -43,27 +43,31 @@ impl<'at> AtPath<'at> {
         AtPath { subdir : Cow::Owned(PathBuf::from(settings.as_ref().tmpd.path())), settings: Some(settings)}
     }
     
+    /// the directory as a string. Paths which aren't valid unicode are
+    /// quoted and escaped, so use subdir directly to operate on them
     pub fn as_string(&self) -> String {
-        self.subdir.to_str().unwrap().to_owned()
+        os_display(self.subdir.as_ref())
     }
 
-    pub fn plus(&self, name: &str) -> PathBuf {
+    pub fn plus<P: AsRef<Path>>(&self, name: P) -> PathBuf {
         let mut pathbuf = PathBuf::from(self.subdir.as_ref());
         pathbuf.push(name);
         pathbuf
     }
 
-    pub fn plus_as_string(&self, name: &str) -> String {
-        String::from(self.plus(name).to_str().unwrap())
+    /// like plus(...), but as a string, escaped as by as_string() if not valid unicode
+    pub fn plus_as_string<P: AsRef<Path>>(&self, name: P) -> String {
+        os_display(self.plus(name))
     }
 
-    fn minus(&self, name: &str) -> PathBuf {
-        let prefixed = PathBuf::from(name);
+    /// the path relative to the directory, if it is within it
+    pub fn minus<P: AsRef<Path>>(&self, name: P) -> PathBuf {
+        let prefixed = PathBuf::from(name.as_ref());
         if prefixed.starts_with(&self.subdir) {
             let mut unprefixed = PathBuf::new();
             for component in prefixed.components()
                                      .skip(self.subdir.components().count()) {
-                unprefixed.push(component.as_ref().to_str().unwrap());
+                unprefixed.push(component.as_os_str());
             }
             unprefixed
         } else {
@@ -71,100 +75,112 @@ impl<'at> AtPath<'at> {
         }
     }
 
-    pub fn minus_as_string(&self, name: &str) -> String {
-        String::from(self.minus(name).to_str().unwrap())
+    /// like minus(...), but as a string, escaped as by as_string() if not valid unicode
+    pub fn minus_as_string<P: AsRef<Path>>(&self, name: P) -> String {
+        os_display(self.minus(name))
     }
 
-    pub fn open(&self, name: &str) -> File {
-        log_info("open", self.plus_as_string(name));
+    pub fn open<P: AsRef<Path>>(&self, name: P) -> File {
+        log_info("open", self.plus_as_string(&name));
         File::open(self.plus(name)).unwrap()
     }
 
-    pub fn read(&self, name: &str) -> String {
+    pub fn read<P: AsRef<Path>>(&self, name: P) -> String {
         let mut f = self.open(name);
         let mut contents = String::new();
         let _ = f.read_to_string(&mut contents);
         contents
     }
 
-    pub fn write(&self, name: &str, contents: &str) {
+    pub fn read_bytes<P: AsRef<Path>>(&self, name: P) -> Vec<u8> {
         let mut f = self.open(name);
-        let _ = f.write(contents.as_bytes());
+        let mut contents = Vec::new();
+        f.read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    /// writes the contents (text or bytes) to the file, creating or truncating it
+    pub fn write<P: AsRef<Path>, C: AsRef<[u8]>>(&self, name: P, contents: C) {
+        log_info("open(write)", self.plus_as_string(&name));
+        let mut f = File::create(self.plus(name)).unwrap();
+        f.write_all(contents.as_ref()).unwrap();
     }
 
-    pub fn append(&self, name: &str, contents: &str) {
-        log_info("open(append)", self.plus_as_string(name));
+    pub fn append<P: AsRef<Path>, C: AsRef<[u8]>>(&self, name: P, contents: C) {
+        log_info("open(append)", self.plus_as_string(&name));
         let mut f = OpenOptions::new().write(true).append(true).open(self.plus(name)).unwrap();
-        let _ = f.write(contents.as_bytes());
+        let _ = f.write(contents.as_ref());
     }
 
-    pub fn mkdir(&self, dir: &str) {
-        log_info("mkdir", self.plus_as_string(dir));
+    pub fn mkdir<P: AsRef<Path>>(&self, dir: P) {
+        log_info("mkdir", self.plus_as_string(&dir));
         fs::create_dir(&self.plus(dir)).unwrap();
     }
-    pub fn mkdir_all(&self, dir: &str) {
-        log_info("mkdir_all", self.plus_as_string(dir));
+    pub fn mkdir_all<P: AsRef<Path>>(&self, dir: P) {
+        log_info("mkdir_all", self.plus_as_string(&dir));
         fs::create_dir_all(self.plus(dir)).unwrap();
     }
 
-    pub fn make_file(&self, name: &str) -> File {
+    pub fn make_file<P: AsRef<Path>>(&self, name: P) -> File {
         match File::create(&self.plus(name)) {
             Ok(f) => f,
             Err(e) => panic!("{}", e),
         }
     }
 
-    pub fn touch(&self, file: &str) {
-        log_info("touch", self.plus_as_string(file));
+    pub fn touch<P: AsRef<Path>>(&self, file: P) {
+        log_info("touch", self.plus_as_string(&file));
         File::create(&self.plus(file)).unwrap();
     }
 
-    pub fn symlink(&self, src: &str, dst: &str) {
+    pub fn symlink<P: AsRef<Path>, Q: AsRef<Path>>(&self, src: P, dst: Q) {
         log_info("symlink",
-                 &format!("{},{}", self.plus_as_string(src), self.plus_as_string(dst)));
+                 format!("{},{}", self.plus_as_string(&src), self.plus_as_string(&dst)));
         symlink_file(&self.plus(src), &self.plus(dst)).unwrap();
     }
 
-    pub fn is_symlink(&self, path: &str) -> bool {
-        log_info("is_symlink", self.plus_as_string(path));
+    pub fn is_symlink<P: AsRef<Path>>(&self, path: P) -> bool {
+        log_info("is_symlink", self.plus_as_string(&path));
         match fs::symlink_metadata(&self.plus(path)) {
             Ok(m) => m.file_type().is_symlink(),
             Err(_) => false,
         }
     }
 
-    pub fn resolve_link(&self, path: &str) -> String {
-        log_info("resolve_link", self.plus_as_string(path));
-        match fs::read_link(&self.plus(path)) {
-            Ok(p) => {
-                self.minus_as_string(p.to_str().unwrap())
-            }
-            Err(_) => "".to_string(),
-        }
+    /// the target of the link, relative to the directory if within it,
+    /// or an empty string if it isn't a link
+    pub fn resolve_link<P: AsRef<Path>>(&self, path: P) -> String {
+        self.resolve_link_path(path).map(os_display).unwrap_or_default()
+    }
+
+    /// like resolve_link(...), but as a path, or None if it isn't a link
+    pub fn resolve_link_path<P: AsRef<Path>>(&self, path: P) -> Option<PathBuf> {
+        log_info("resolve_link", self.plus_as_string(&path));
+        fs::read_link(self.plus(path)).ok().map(|target| self.minus(target))
     }
 
-    pub fn metadata(&self, path: &str) -> fs::Metadata {
+    pub fn metadata<P: AsRef<Path>>(&self, path: P) -> fs::Metadata {
         match fs::metadata(&self.plus(path)) {
             Ok(m) => m,
             Err(e) => panic!("{}", e),
         }
     }
 
-    pub fn file_exists(&self, path: &str) -> bool {
+    pub fn file_exists<P: AsRef<Path>>(&self, path: P) -> bool {
         match fs::metadata(&self.plus(path)) {
             Ok(m) => m.is_file(),
             Err(_) => false,
         }
     }
 
-    pub fn dir_exists(&self, path: &str) -> bool {
+    pub fn dir_exists<P: AsRef<Path>>(&self, path: P) -> bool {
         match fs::metadata(&self.plus(path)) {
             Ok(m) => m.is_dir(),
             Err(_) => false,
         }
     }
 
-    pub fn cleanup(&self, path: &'static str) {
+    pub fn cleanup<P: AsRef<Path>>(&self, path: P) {
         let p = &self.plus(path);
         match fs::metadata(p) {
             Ok(m) => if m.is_file() {
@@ -178,36 +194,41 @@ impl<'at> AtPath<'at> {
 
     pub fn root_dir(&self) -> String {
         log_info("current_directory", "");
-        self.subdir.to_str().unwrap().to_owned()
+        os_display(self.subdir.as_ref())
     }
 
     pub fn root_dir_resolved(&self) -> Option<String> {
+        self.root_dir_resolved_path().map(os_display)
+    }
+
+    /// like root_dir_resolved(...), but as a path, so that it's kept as is if it isn't valid unicode
+    pub fn root_dir_resolved_path(&self) -> Option<PathBuf> {
         log_info("current_directory_resolved", "");
-        let c = self.subdir.canonicalize();
-        match c {
-            Result::Err(_) => None,
-            Result::Ok(x) => {
-                match x.to_str() {
-                    None => None,
-                    Some(sb) => Some({
-                        let s = sb.to_owned();
-                        // Due to canonicalize()'s use of GetFinalPathNameByHandleW() on Windows, the resolved path
-                        // starts with '\\?\' to extend the limit of a given path to 32,767 wide characters.
-                        //
-                        // To address this issue, we remove this prepended string if available.
-                        //
-                        // Source:
-                        // http://stackoverflow.com/questions/31439011/getfinalpathnamebyhandle-without-prepended
-                        let prefix = "\\\\?\\";
-                        if s.starts_with(prefix) {
-                            String::from(&s[prefix.len()..])
-                        } else {
-                            s
-                        }
-                    })
-                }
-            }
-        }
+        self.subdir.canonicalize().ok().map(without_verbatim_prefix)
+    }
+}
 
+// Due to canonicalize()'s use of GetFinalPathNameByHandleW() on Windows, the resolved path
+// starts with '\\?\' to extend the limit of a given path to 32,767 wide characters.
+//
+// To address this issue, we remove this prepended string if available.
+//
+// Source:
+// http://stackoverflow.com/questions/31439011/getfinalpathnamebyhandle-without-prepended
+#[cfg(windows)]
+fn without_verbatim_prefix(path: PathBuf) -> PathBuf {
+    use std::ffi::OsString;
+    use std::os::windows::ffi::{OsStrExt, OsStringExt};
+    let prefix: Vec<u16> = "\\\\?\\".encode_utf16().collect();
+    let wide: Vec<u16> = path.as_os_str().encode_wide().collect();
+    if wide.starts_with(&prefix) {
+        PathBuf::from(OsString::from_wide(&wide[prefix.len()..]))
+    } else {
+        path
     }
 }
+
+#[cfg(not(windows))]
+fn without_verbatim_prefix(path: PathBuf) -> PathBuf {
+    path
+}
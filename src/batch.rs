@@ -0,0 +1,158 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::thread;
+
+use super::cmdresult::CmdResult;
+use super::common::log_info;
+use super::ucommand::UCommand;
+
+/// A Batch runs many independent UCommands concurrently, on a bounded number of
+/// worker threads, for table-driven tests with many invocations.
+/// Unlike running each command in turn, a failing case doesn't stop the remaining
+/// cases from running, and every failure is reported together.
+pub struct Batch {
+    commands: Vec<UCommand>,
+    workers: usize,
+}
+
+/// The outputs of every command of a batch
+pub struct BatchResult {
+    /// the result of each command, in the order the commands were provided
+    pub results: Vec<CmdResult>,
+    comm_strings: Vec<String>,
+}
+
+// describes the payload of a panic, for reporting it without unwinding
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        String::from(*msg)
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        String::from("(non-string panic)")
+    }
+}
+
+impl Batch {
+    /// a batch of the provided commands, run with as many workers as
+    /// the machine has available cores
+    pub fn new(commands: Vec<UCommand>) -> Batch {
+        Batch {
+            commands,
+            workers: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        }
+    }
+
+    /// sets the maximum number of commands run at once
+    pub fn workers(&mut self, workers: usize) -> Box<&mut Batch> {
+        if workers == 0 {
+            panic!("a batch needs at least one worker");
+        }
+        self.workers = workers;
+        Box::new(self)
+    }
+
+    /// adds another command to the batch
+    pub fn push(&mut self, command: UCommand) -> Box<&mut Batch> {
+        self.commands.push(command);
+        Box::new(self)
+    }
+
+    /// Runs every command, at most the worker count at once, waits for
+    /// all of them to complete, and returns their results in order.
+    /// If any command can't be run, the others are still run before panicking.
+    pub fn run(&mut self) -> BatchResult {
+        let comm_strings: Vec<String> = self.commands.iter().map(|command| String::from(command.comm_string())).collect();
+        log_info("run batch", format!("{} commands on {} workers", self.commands.len(), self.workers));
+        let results: Mutex<Vec<Option<thread::Result<CmdResult>>>> =
+            Mutex::new(self.commands.iter().map(|_| None).collect());
+        {
+            let workers = self.workers.min(comm_strings.len());
+            let pending = Mutex::new(self.commands.iter_mut().enumerate());
+            thread::scope(|scope| {
+                for _ in 0..workers {
+                    scope.spawn(|| {
+                        loop {
+                            let next = pending.lock().unwrap().next();
+                            let (i, command) = match next {
+                                Some(next) => next,
+                                None => break,
+                            };
+                            let result = panic::catch_unwind(AssertUnwindSafe(|| command.run()));
+                            results.lock().unwrap()[i] = Some(result);
+                        }
+                    });
+                }
+            });
+        }
+
+        let mut completed = Vec::new();
+        let mut errors = Vec::new();
+        for (i, result) in results.into_inner().unwrap().into_iter().enumerate() {
+            match result.expect("every command of a batch is run") {
+                Ok(result) => completed.push(result),
+                Err(payload) => errors.push(format!("  [{}] {}\n      {}", i, comm_strings[i], panic_message(&*payload))),
+            }
+        }
+        if !errors.is_empty() {
+            for result in completed.iter() {
+                if let Some(ref repro) = result.repro {
+                    repro.dismiss();
+                }
+            }
+            panic!("{} of {} commands of the batch could not be run:\n{}",
+                   errors.len(), comm_strings.len(), errors.join("\n"));
+        }
+        BatchResult {
+            results: completed,
+            comm_strings,
+        }
+    }
+
+    /// Runs the batch and asserts that every command succeeded
+    pub fn succeeds(&mut self) -> BatchResult {
+        let result = self.run();
+        result.success();
+        result
+    }
+}
+
+impl BatchResult {
+    /// returns the result of the command at the provided index
+    pub fn result(&self, index: usize) -> &CmdResult {
+        &self.results[index]
+    }
+
+    /// runs the provided assertions against the result of every command, along with its index,
+    /// then panics reporting every case whose assertions failed, if any.
+    /// a script reproducing each failing case is written out, as for a single failed command.
+    pub fn check_each<F: Fn(usize, &CmdResult)>(&self, check: F) -> Box<&BatchResult> {
+        let mut failures = Vec::new();
+        for (i, result) in self.results.iter().enumerate() {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| check(i, result))) {
+                let repro = result.repro.as_ref()
+                                  .and_then(|repro| repro.write_once())
+                                  .map(|script| format!("\n      repro: {}", script.to_string_lossy()))
+                                  .unwrap_or(String::new());
+                failures.push(format!("  [{}] {}\n      {}{}", i, self.comm_strings[i], panic_message(&*payload), repro));
+            }
+        }
+        if !failures.is_empty() {
+            // the passing results are still in scope while panicking
+            for result in self.results.iter() {
+                if let Some(ref repro) = result.repro {
+                    repro.dismiss();
+                }
+            }
+            panic!("{} of {} commands of the batch failed:\n{}",
+                   failures.len(), self.results.len(), failures.join("\n"));
+        }
+        Box::new(self)
+    }
+
+    /// asserts that every command resulted in a success (zero) status code
+    pub fn success(&self) -> Box<&BatchResult> {
+        self.check_each(|_, result| { result.success(); })
+    }
+}
@@ -0,0 +1,155 @@
+use std::io::{ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// One of the output streams of a command
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// A chunk of output, as read from one of a command's output streams
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputChunk {
+    pub stream: Stream,
+    /// time elapsed between spawning the command and reading the chunk
+    pub elapsed: Duration,
+    pub bytes: Vec<u8>,
+}
+
+/// A record, shared between the captures of a command's output streams,
+/// of the chunks read from each in the order they were read.
+#[derive(Clone)]
+pub struct CombinedLog {
+    chunks: Arc<Mutex<Vec<OutputChunk>>>,
+    started: Instant,
+}
+
+impl CombinedLog {
+    pub fn new(started: Instant) -> CombinedLog {
+        CombinedLog {
+            chunks: Arc::new(Mutex::new(Vec::new())),
+            started,
+        }
+    }
+
+    fn record(&self, stream: Stream, bytes: &[u8]) {
+        self.chunks.lock().unwrap().push(OutputChunk {
+            stream,
+            elapsed: self.started.elapsed(),
+            bytes: bytes.to_vec(),
+        });
+    }
+
+    pub fn chunks(&self) -> Vec<OutputChunk> {
+        self.chunks.lock().unwrap().clone()
+    }
+}
+
+/// Drains an output stream of a child process on a separate thread,
+/// so that the content read so far remains available even if the stream
+/// is never closed (e.g. when the process has to be killed).
+pub struct Capture {
+    buffer: Arc<Mutex<Vec<u8>>>,
+    closed: Receiver<()>,
+}
+
+impl Capture {
+    pub fn spawn<R: Read + Send + 'static>(stream: R) -> Capture {
+        Capture::spawn_logged(stream, None, None)
+    }
+
+    /// like spawn(...), but if a limit is provided, stops reading and closes
+    /// the stream once that many bytes have been read.
+    /// if a combined log is provided, each chunk read is also recorded there as coming from the provided stream.
+    pub fn spawn_logged<R: Read + Send + 'static>(mut stream: R, limit: Option<usize>, log: Option<(CombinedLog, Stream)>) -> Capture {
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let (sender, closed) = channel();
+        {
+            let buffer = buffer.clone();
+            thread::spawn(move || {
+                let mut chunk = [0; 8192];
+                let mut remaining = limit.unwrap_or(usize::MAX);
+                while remaining > 0 {
+                    let chunk_len = remaining.min(chunk.len());
+                    match stream.read(&mut chunk[..chunk_len]) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            buffer.lock().unwrap().extend_from_slice(&chunk[..n]);
+                            if let Some((ref log, stream)) = log {
+                                log.record(stream, &chunk[..n]);
+                            }
+                            remaining -= n;
+                        }
+                    }
+                }
+                drop(stream);
+                let _ = sender.send(());
+            });
+        }
+        Capture {
+            buffer,
+            closed,
+        }
+    }
+
+    /// a capture of a stream that doesn't exist, which is empty and already closed
+    pub fn closed() -> Capture {
+        let (_, closed) = channel();
+        Capture {
+            buffer: Arc::new(Mutex::new(Vec::new())),
+            closed,
+        }
+    }
+
+    /// blocks until the stream is closed, or until the deadline if one is provided.
+    /// returns whether the stream was closed.
+    pub fn wait_closed(&self, deadline: Option<Instant>) -> bool {
+        // the reading thread drops its sender when it finishes, so a disconnect
+        // means the stream was closed on a previous call
+        match deadline {
+            None => {
+                let _ = self.closed.recv();
+                true
+            },
+            Some(deadline) => {
+                let now = Instant::now();
+                let remaining = if deadline > now { deadline - now } else { Default::default() };
+                self.closed.recv_timeout(remaining) != Err(RecvTimeoutError::Timeout)
+            }
+        }
+    }
+
+    /// returns whether the stream has been closed, without blocking
+    pub fn is_closed(&self) -> bool {
+        self.wait_closed(Some(Instant::now()))
+    }
+
+    /// returns everything read from the stream so far
+    pub fn contents(&self) -> Vec<u8> {
+        self.buffer.lock().unwrap().clone()
+    }
+
+    /// returns everything read from the stream so far, past the provided offset
+    pub fn contents_from(&self, offset: usize) -> Vec<u8> {
+        let buffer = self.buffer.lock().unwrap();
+        buffer[offset.min(buffer.len())..].to_vec()
+    }
+}
+
+/// writes the input to a child process's stream on a separate thread, closing the
+/// stream afterwards, so that a process which fills its output pipes before
+/// consuming all of its input can't deadlock against the caller.
+pub fn feed<W: Write + Send + 'static>(mut stream: W, input: Vec<u8>) {
+    thread::spawn(move || {
+        match stream.write_all(&input) {
+            // the process exited, or closed its stdin, without consuming all of it
+            Err(ref e) if e.kind() == ErrorKind::BrokenPipe => {},
+            Err(e) => panic!("failed writing to child process stdin: {}", e),
+            Ok(()) => {}
+        }
+    });
+}
@@ -1,31 +1,168 @@
 extern crate tempdir;
+#[cfg(unix)]
+extern crate libc;
 
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
+use std::process::ExitStatus;
 use std::sync::Arc;
+use std::time::Duration;
 
-use super::fixtures::read_scenario_fixture;
+use super::capture::{OutputChunk, Stream};
+use super::common::hex_diff;
+use super::fixtures::{read_scenario_fixture, read_scenario_fixture_bytes};
+use super::process::{LeftoverProcess, ResourceUsage};
+use super::repro::ReproGuard;
 use super::settings::SceneSettings;
 
+// the exit code of a process which panicked on its main thread
+static RUST_PANIC_CODE: i32 = 101;
+
 /// A command result is the outputs of a command (streams and status code)
 /// within a struct which has convenience assertion functions about those outputs
 pub struct CmdResult {
     //tmpd is used for convenience functions for asserts against fixtures
     pub settings: Arc<SceneSettings>,
     pub success: bool,
+    /// the exit status of the command, for inspecting its exit code or terminating signal
+    pub status: ExitStatus,
+    /// whether the command was killed for running past its timeout
+    pub timed_out: bool,
+    /// stdout decoded as UTF-8, with any invalid sequences replaced by U+FFFD
     pub stdout: String,
+    /// stderr decoded as UTF-8, with any invalid sequences replaced by U+FFFD
     pub stderr: String,
+    /// the exact bytes written to stdout
+    pub stdout_raw: Vec<u8>,
+    /// the exact bytes written to stderr
+    pub stderr_raw: Vec<u8>,
+    /// time elapsed between spawning the command and it exiting
+    pub wall_time: Duration,
+    /// cpu time and peak memory used by the command.
+    /// None on platforms which don't report resource usage of child processes
+    pub usage: Option<ResourceUsage>,
+    /// the chunks of output read from stdout and stderr, in the order they arrived.
+    /// None unless UCommand::capture_combined() was used
+    pub combined: Option<Vec<OutputChunk>>,
+    /// everything written to additional file descriptors passed with
+    /// UCommand::pass_fd(n, FdSource::Capture), by descriptor number
+    pub fds: BTreeMap<i32, Vec<u8>>,
+    /// processes left running in the command's process group after it exited.
+    /// None unless UCommand::track_leftover_processes() or kill_leftover_processes() was used
+    pub leftover_processes: Option<Vec<LeftoverProcess>>,
+    // written out if an assertion fails while the result is in scope
+    pub(crate) repro: Option<ReproGuard>,
 }
 
 impl CmdResult {
     /// asserts that the command resulted in a success (zero) status code
     pub fn success(&self) -> Box<&CmdResult> {
-        assert!(self.success);
+        assert!(!self.timed_out, "the command timed out");
+        assert!(self.success, "expected success, but the command ended with {}", self.status);
         Box::new(self)
     }
 
     /// asserts that the command resulted in a failure (non-zero) status code
     pub fn failure(&self) -> Box<&CmdResult> {
-        assert!(!self.success);
+        assert!(!self.timed_out, "the command timed out");
+        assert!(!self.success, "expected failure, but the command ended with {}", self.status);
+        Box::new(self)
+    }
+
+    /// returns the exit code of the command, or None if it was terminated by a signal
+    pub fn code(&self) -> Option<i32> {
+        self.status.code()
+    }
+
+    /// returns the signal that terminated the command, or None if it exited normally.
+    /// always None on platforms without signals
+    #[cfg(unix)]
+    pub fn signal(&self) -> Option<i32> {
+        use std::os::unix::process::ExitStatusExt;
+        self.status.signal()
+    }
+
+    #[cfg(not(unix))]
+    pub fn signal(&self) -> Option<i32> {
+        None
+    }
+
+    /// asserts that the command exited with the provided exit code
+    pub fn code_is(&self, expected_code: i32) -> Box<&CmdResult> {
+        assert_eq!(Some(expected_code), self.code(),
+                   "expected exit code {}, but the command ended with {}", expected_code, self.status);
+        Box::new(self)
+    }
+
+    /// asserts that the command exited with any one of the provided exit codes
+    pub fn code_in(&self, expected_codes: &[i32]) -> Box<&CmdResult> {
+        assert!(self.code().is_some_and(|code| expected_codes.contains(&code)),
+                "expected one of exit codes {:?}, but the command ended with {}", expected_codes, self.status);
+        Box::new(self)
+    }
+
+    /// asserts that the command was terminated by the provided signal (e.g. libc::SIGABRT)
+    pub fn signaled_with(&self, expected_signal: i32) -> Box<&CmdResult> {
+        assert_eq!(Some(expected_signal), self.signal(),
+                   "expected termination by signal {}, but the command ended with {}", expected_signal, self.status);
+        Box::new(self)
+    }
+
+    /// asserts that the command exited on its own rather than being terminated by a signal
+    pub fn not_signaled(&self) -> Box<&CmdResult> {
+        assert!(self.signal().is_none(), "expected no terminating signal, but the command ended with {}", self.status);
+        Box::new(self)
+    }
+
+    /// asserts that the command was killed for running past its timeout.
+    /// stdout and stderr hold whatever output was captured before it was killed
+    pub fn timed_out(&self) -> Box<&CmdResult> {
+        assert!(self.timed_out, "the command did not time out");
+        Box::new(self)
+    }
+
+    /// returns the exact bytes written to stdout
+    pub fn stdout_bytes(&self) -> &[u8] {
+        &self.stdout_raw
+    }
+
+    /// returns the exact bytes written to stderr
+    pub fn stderr_bytes(&self) -> &[u8] {
+        &self.stderr_raw
+    }
+
+    /// returns stdout as text, with any invalid UTF-8 replaced by U+FFFD
+    pub fn stdout_lossy(&self) -> &str {
+        &self.stdout
+    }
+
+    /// returns stderr as text, with any invalid UTF-8 replaced by U+FFFD
+    pub fn stderr_lossy(&self) -> &str {
+        &self.stderr
+    }
+
+    /// returns everything written to the additional file descriptor n,
+    /// which must have been passed with UCommand::pass_fd(n, FdSource::Capture)
+    pub fn fd_bytes(&self, n: i32) -> &[u8] {
+        self.fds.get(&n).unwrap_or_else(|| panic!("file descriptor {} was not passed to the command as FdSource::Capture", n))
+    }
+
+    /// asserts that the output written to the additional file descriptor n equals the
+    /// passed in value, when both are trimmed of trailing whitespace
+    pub fn fd_is<T: AsRef<str>>(&self, n: i32, msg: T) -> Box<&CmdResult> {
+        assert_eq!(String::from(msg.as_ref()).trim_end(), String::from_utf8_lossy(self.fd_bytes(n)).trim_end());
+        Box::new(self)
+    }
+
+    /// asserts that the command left no processes running in its process group once it exited
+    /// (including zombies, which exited but weren't reaped by their parent).
+    /// requires UCommand::track_leftover_processes() or kill_leftover_processes()
+    pub fn no_leftover_processes(&self) -> Box<&CmdResult> {
+        match self.leftover_processes {
+            None => panic!("leftover processes weren't tracked, use UCommand::track_leftover_processes()"),
+            Some(ref leftover) => assert!(leftover.is_empty(),
+                                          "expected no leftover processes, but found {:?}", leftover),
+        }
         Box::new(self)
     }
 
@@ -64,6 +201,22 @@ impl CmdResult {
         self.stdout_is(contents)
     }
 
+    /// asserts that the command resulted in stdout stream output that is byte-for-byte
+    /// identical to the passed in value. Nothing is trimmed.
+    /// On failure, hex dumps of both around the first difference are displayed.
+    pub fn stdout_is_bytes<T: AsRef<[u8]>>(&self, expected: T) -> Box<&CmdResult> {
+        if expected.as_ref() != &self.stdout_raw[..] {
+            panic!("stdout did not match: {}", hex_diff(expected.as_ref(), &self.stdout_raw));
+        }
+        Box::new(self)
+    }
+
+    /// like stdout_is_bytes(...), but expects the contents of the file at the provided relative path
+    pub fn stdout_is_fixture_bytes<T: AsRef<OsStr>>(&self, file_rel_path: T) -> Box<&CmdResult> {
+        let contents = read_scenario_fixture_bytes(&self.settings, file_rel_path);
+        self.stdout_is_bytes(contents)
+    }
+
     /// asserts that the command resulted in stderr stream output that equals the
     /// passed in value, when both are trimmed of trailing whitespace
     /// stderr_only is a better choice unless stdout may or will be non-empty
@@ -78,6 +231,20 @@ impl CmdResult {
         self.stderr_is(contents)
     }
 
+    /// like stdout_is_bytes(...), but for stderr
+    pub fn stderr_is_bytes<T: AsRef<[u8]>>(&self, expected: T) -> Box<&CmdResult> {
+        if expected.as_ref() != &self.stderr_raw[..] {
+            panic!("stderr did not match: {}", hex_diff(expected.as_ref(), &self.stderr_raw));
+        }
+        Box::new(self)
+    }
+
+    /// like stderr_is_bytes(...), but expects the contents of the file at the provided relative path
+    pub fn stderr_is_fixture_bytes<T: AsRef<OsStr>>(&self, file_rel_path: T) -> Box<&CmdResult> {
+        let contents = read_scenario_fixture_bytes(&self.settings, file_rel_path);
+        self.stderr_is_bytes(contents)
+    }
+
     /// asserts that
     /// 1. the command resulted in stdout stream output that equals the
     /// passed in value, when both are trimmed of trailing whitespace
@@ -106,8 +273,117 @@ impl CmdResult {
         self.stderr_only(contents)
     }
 
+    /// returns the chunks of output read from stdout and stderr, in the order they arrived.
+    /// requires that UCommand::capture_combined() was used
+    pub fn combined_chunks(&self) -> &[OutputChunk] {
+        self.combined.as_ref().expect("combined output was not captured, use UCommand::capture_combined()")
+    }
+
+    /// returns the output of stdout and stderr merged in the order it arrived,
+    /// with any invalid UTF-8 replaced by U+FFFD.
+    /// requires that UCommand::capture_combined() was used
+    pub fn combined_output(&self) -> String {
+        let merged: Vec<u8> = self.combined_chunks().iter()
+                                  .flat_map(|chunk| chunk.bytes.iter().cloned())
+                                  .collect();
+        String::from_utf8_lossy(&merged).into_owned()
+    }
+
+    /// asserts that the merged output of stdout and stderr equals the
+    /// passed in value, when both are trimmed of trailing whitespace
+    pub fn combined_output_is<T: AsRef<str>>(&self, msg: T) -> Box<&CmdResult> {
+        assert_eq!(String::from(msg.as_ref()).trim_end(), self.combined_output().trim_end());
+        Box::new(self)
+    }
+
+    /// asserts that, in the merged output of stdout and stderr, the first text appears
+    /// in output of the first stream, and that it arrived before the second text
+    /// appeared in output of the second stream.
+    /// e.g. output_order((Stream::Stderr, "warning:"), (Stream::Stdout, "result"))
+    pub fn output_order<T: AsRef<str>, U: AsRef<str>>(&self, first: (Stream, T), second: (Stream, U)) -> Box<&CmdResult> {
+        let first_at = self.arrival_of(first.0, first.1.as_ref());
+        let second_at = self.arrival_of(second.0, second.1.as_ref());
+        assert!(first_at < second_at,
+                "expected {:?} on {:?} to arrive before {:?} on {:?}, but it arrived after. combined output: {:?}",
+                first.1.as_ref(), first.0, second.1.as_ref(), second.0, self.combined_output());
+        Box::new(self)
+    }
+
+    // the offset in the combined output at which the text was complete,
+    // when searching only within output of the provided stream
+    fn arrival_of(&self, stream: Stream, text: &str) -> usize {
+        let mut stream_output = Vec::new();
+        let mut combined_offset = 0;
+        for chunk in self.combined_chunks().iter() {
+            combined_offset += chunk.bytes.len();
+            if chunk.stream != stream {
+                continue;
+            }
+            let searched_from = stream_output.len().saturating_sub(text.len());
+            stream_output.extend_from_slice(&chunk.bytes);
+            let found = stream_output[searched_from..].windows(text.len().max(1))
+                                                      .position(|window| window == text.as_bytes());
+            if let Some(position) = found {
+                // the offset within the combined output at which the text ends
+                let end_in_stream = searched_from + position + text.len();
+                return combined_offset - (stream_output.len() - end_in_stream);
+            }
+        }
+        panic!("expected {:?} to appear on {:?}, but it did not. combined output: {:?}",
+               text, stream, self.combined_output());
+    }
+
+    /// asserts that the command exited within the provided duration of being spawned
+    pub fn completes_within(&self, duration: Duration) -> Box<&CmdResult> {
+        assert!(self.wall_time <= duration,
+                "expected the command to complete within {:?}, but it took {:?}", duration, self.wall_time);
+        Box::new(self)
+    }
+
+    /// asserts that the peak resident set size (physical memory use) of the command
+    /// was below the provided number of bytes. Only supported on unix platforms.
+    pub fn max_rss_below(&self, bytes: u64) -> Box<&CmdResult> {
+        let usage = self.usage.expect("resource usage is not available on this platform");
+        assert!(usage.max_rss < bytes,
+                "expected peak memory use below {} bytes, but it was {} bytes", bytes, usage.max_rss);
+        Box::new(self)
+    }
+
+    /// asserts that the command's stderr holds no Rust panic message
+    /// and that it did not exit with the status code used for panics (101)
+    pub fn no_panic(&self) -> Box<&CmdResult> {
+        assert!(!self.stderr.contains("panicked at"), "expected no panic, but stderr was: {}", self.stderr);
+        assert!(self.code() != Some(RUST_PANIC_CODE), "expected no panic, but the command ended with {}", self.status);
+        Box::new(self)
+    }
+
+    /// for a command whose stdout was closed early with UCommand::stdout_read_limit(...),
+    /// asserts that it handled the broken pipe quietly: it was terminated by SIGPIPE,
+    /// or exited on EPIPE without panicking, and in either case wrote nothing to stderr.
+    pub fn broken_pipe_quietly(&self) -> Box<&CmdResult> {
+        assert!(!self.timed_out, "the command timed out");
+        self.no_panic();
+        if !self.is_sigpipe() {
+            assert!(self.signal().is_none(),
+                    "expected termination by SIGPIPE or exiting, but the command ended with {}", self.status);
+        }
+        assert!(self.stderr_raw.is_empty(), "expected no stderr after the broken pipe, but got: {}", self.stderr);
+        Box::new(self)
+    }
+
+    #[cfg(unix)]
+    fn is_sigpipe(&self) -> bool {
+        self.signal() == Some(libc::SIGPIPE)
+    }
+
+    #[cfg(not(unix))]
+    fn is_sigpipe(&self) -> bool {
+        false
+    }
+
     pub fn fails_silently(&self) -> Box<&CmdResult> {
-        assert!(!self.success);
+        assert!(!self.timed_out, "the command timed out");
+        assert!(!self.success, "expected failure, but the command ended with {}", self.status);
         assert_eq!("", self.stderr);
         Box::new(self)
     }
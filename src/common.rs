@@ -1,9 +1,7 @@
+use std::ffi::{OsStr, OsString};
 use std::fs::{self};
-use std::io::{Read, Result};
+use std::io::Result;
 use std::path::{Path, PathBuf};
-use std::process::Child;
-use std::thread::sleep;
-use std::time::Duration;
 
 #[allow(dead_code)]
 pub fn repeat_str(s: &str, n: u32) -> String {
@@ -15,27 +13,111 @@ pub fn repeat_str(s: &str, n: u32) -> String {
 }
 
 
+/// displays an OS string or path as is if it's valid unicode, otherwise quoted,
+/// with the invalid sequences escaped (e.g. "\xFF") rather than lost
+pub fn os_display<S: AsRef<OsStr>>(s: S) -> String {
+    match s.as_ref().to_str() {
+        Some(valid) => String::from(valid),
+        None => format!("{:?}", s.as_ref()),
+    }
+}
+
+/// converts bytes, such as the contents of a file, to an OS string without loss where
+/// the platform allows arbitrary bytes in one, otherwise replacing invalid unicode
+#[cfg(unix)]
+pub fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+pub fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    OsString::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
 pub fn log_info<T: AsRef<str>, U: AsRef<str>>(msg: T, par: U) {
     println!("{}: {}", msg.as_ref(), par.as_ref());
 }
 
 pub fn recursive_copy(src: &Path, dest: &Path) -> Result<()> {
-    if try!(fs::metadata(src)).is_dir() {
-        for entry in try!(fs::read_dir(src)) {
-            let entry = try!(entry);
+    copy_tree(src, dest, false)
+}
+
+/// like recursive_copy(...), but copies symbolic links as links rather than what they lead to,
+/// so dangling links, link loops and links out of the tree are copied as is, and skips (and logs)
+/// special files such as sockets and fifos, which can't be copied, and which would block reading
+/// in the case of a fifo
+pub fn recursive_copy_skipping_special(src: &Path, dest: &Path) -> Result<()> {
+    copy_tree(src, dest, true)
+}
+
+fn copy_tree(src: &Path, dest: &Path, as_is: bool) -> Result<()> {
+    if fs::metadata(src)?.is_dir() {
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
             let mut new_dest = PathBuf::from(dest);
             new_dest.push(entry.file_name());
-            if try!(fs::metadata(entry.path())).is_dir() {
-                try!(fs::create_dir(&new_dest));
-                try!(recursive_copy(&entry.path(), &new_dest));
+            // unlike fs::metadata(...), DirEntry::metadata() doesn't follow links
+            let metadata = if as_is { entry.metadata()? } else { fs::metadata(entry.path())? };
+            if metadata.file_type().is_symlink() {
+                copy_link(&entry.path(), &new_dest)?;
+            } else if metadata.is_dir() {
+                fs::create_dir(&new_dest)?;
+                copy_tree(&entry.path(), &new_dest, as_is)?;
+            } else if as_is && !metadata.is_file() {
+                log_info("skipped copying special file", os_display(entry.path()));
             } else {
-                try!(fs::copy(&entry.path(), new_dest));
+                fs::copy(entry.path(), new_dest)?;
             }
         }
     }
     Ok(())
 }
 
+#[cfg(unix)]
+fn copy_link(src: &Path, dest: &Path) -> Result<()> {
+    ::std::os::unix::fs::symlink(fs::read_link(src)?, dest)
+}
+
+#[cfg(not(unix))]
+fn copy_link(src: &Path, _: &Path) -> Result<()> {
+    log_info("skipped copying link", os_display(src));
+    Ok(())
+}
+
+static HEX_DUMP_ROW_LEN: usize = 16;
+static HEX_DUMP_CONTEXT_ROWS: usize = 2;
+
+fn hex_dump_rows(bytes: &[u8], first_row: usize, last_row: usize) -> String {
+    let mut dump = String::new();
+    for (row, chunk) in bytes.chunks(HEX_DUMP_ROW_LEN).enumerate()
+                             .skip(first_row).take(last_row + 1 - first_row) {
+        let mut hex = String::new();
+        let mut printable = String::new();
+        for byte in chunk {
+            hex.push_str(&format!("{:02x} ", byte));
+            printable.push(if *byte >= 0x20 && *byte < 0x7f { *byte as char } else { '.' });
+        }
+        dump.push_str(&format!("{:08x}  {:<48} |{}|\n", row * HEX_DUMP_ROW_LEN, hex, printable));
+    }
+    dump
+}
+
+/// describes where two byte sequences first differ, with a hex dump
+/// of each around that offset
+pub fn hex_diff(expected: &[u8], actual: &[u8]) -> String {
+    let first_difference = expected.iter().zip(actual.iter())
+                                   .position(|(e, a)| e != a)
+                                   .unwrap_or(expected.len().min(actual.len()));
+    let diff_row = first_difference / HEX_DUMP_ROW_LEN;
+    let first_row = diff_row.saturating_sub(HEX_DUMP_CONTEXT_ROWS);
+    let last_row = diff_row + HEX_DUMP_CONTEXT_ROWS;
+    format!("expected {} bytes, got {} bytes, first difference at offset {:#x}\nexpected:\n{}actual:\n{}",
+            expected.len(), actual.len(), first_difference,
+            hex_dump_rows(expected, first_row, last_row),
+            hex_dump_rows(actual, first_row, last_row))
+}
+
 #[allow(dead_code)]
 pub fn get_root_path() -> &'static str {
     if cfg!(windows) {
@@ -44,12 +126,3 @@ pub fn get_root_path() -> &'static str {
         "/"
     }
 }
-
-#[allow(dead_code)]
-pub fn read_size(child: &mut Child, size: usize) -> String {
-    let mut output = Vec::new();
-    output.resize(size, 0);
-    sleep(Duration::from_secs(1));
-    child.stdout.as_mut().unwrap().read(output.as_mut_slice()).unwrap();
-    String::from_utf8(output).unwrap()
-}
@@ -8,5 +8,10 @@ use super::settings::SceneSettings;
 
 pub fn read_scenario_fixture<S: AsRef<OsStr>>(settings: &Arc<SceneSettings>, file_rel_path: S) -> String {
     let tmpdir_path = settings.as_ref().tmpd.path();
-    AtPath::from_path(tmpdir_path).read(file_rel_path.as_ref().to_str().unwrap())
+    AtPath::from_path(tmpdir_path).read(file_rel_path.as_ref())
+}
+
+pub fn read_scenario_fixture_bytes<S: AsRef<OsStr>>(settings: &Arc<SceneSettings>, file_rel_path: S) -> Vec<u8> {
+    let tmpdir_path = settings.as_ref().tmpd.path();
+    AtPath::from_path(tmpdir_path).read_bytes(file_rel_path.as_ref())
 }
@@ -0,0 +1,343 @@
+use std::ffi::{OsStr, OsString};
+use std::io::{BufRead, BufReader, Read, Result, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use super::atpath::AtPath;
+use super::common::log_info;
+
+/// replaced with the base url of the scene's http stub (e.g. "http://127.0.0.1:41234")
+/// wherever it appears in the arguments or environment variables of a command
+pub static HTTP_STUB_PLACEHOLDER: &str = "{http_stub}";
+
+static CONNECTION_TIMEOUT_SECS: u64 = 10;
+static NOT_STUBBED_STATUS: u16 = 404;
+
+/// A request received by an HttpStub
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// the request target, including any query string
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl RecordedRequest {
+    /// returns the value of the first header with the provided name, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// returns the body of the request, replacing any invalid unicode
+    pub fn body_str(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    // the path without the query string
+    fn path_only(&self) -> &str {
+        self.path.split('?').next().unwrap()
+    }
+
+    fn matches(&self, method: &str, path: &str) -> bool {
+        self.method.eq_ignore_ascii_case(method) && (self.path == path || self.path_only() == path)
+    }
+}
+
+struct Route {
+    method: String,
+    path: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+struct StubState {
+    addr: SocketAddr,
+    tmpdir: PathBuf,
+    routes: Mutex<Vec<Route>>,
+    requests: Mutex<Vec<RecordedRequest>>,
+    shutdown: AtomicBool,
+}
+
+/// An HttpStub is a handle to the local http server of a scene, which answers
+/// requests with canned responses and records them, so that binaries which call an
+/// http api can be tested offline, then asserted on for the calls they made.
+/// A request for which no route has been added is answered with 404.
+#[derive(Clone)]
+pub struct HttpStub {
+    state: Arc<StubState>,
+}
+
+impl HttpStub {
+    /// returns the base url of the server, e.g. "http://127.0.0.1:41234", without a trailing slash
+    pub fn url(&self) -> String {
+        format!("http://{}", self.state.addr)
+    }
+
+    /// returns the address the server listens on
+    pub fn addr(&self) -> SocketAddr {
+        self.state.addr
+    }
+
+    /// answers requests with the provided method and path with the provided status and body.
+    /// a path without a query string matches requests with any query string.
+    /// If several routes match a request, the most recently added is used.
+    pub fn route<B: AsRef<[u8]>>(&self, method: &str, path: &str, status: u16, body: B) -> Box<&HttpStub> {
+        self.route_with_headers(method, path, status, &[], body)
+    }
+
+    /// like route(...), but also sends the provided response headers, e.g. &[("Content-Type", "application/json")]
+    pub fn route_with_headers<B: AsRef<[u8]>>(&self, method: &str, path: &str, status: u16,
+                                              headers: &[(&str, &str)], body: B) -> Box<&HttpStub> {
+        self.state.routes.lock().unwrap().push(Route {
+            method: method.to_uppercase(),
+            path: String::from(path),
+            status,
+            headers: headers.iter().map(|&(key, value)| (String::from(key), String::from(value))).collect(),
+            body: body.as_ref().to_vec(),
+        });
+        Box::new(self)
+    }
+
+    /// like route(...), but uses the contents of the file at the provided path relative to
+    /// the scene's temporary directory, as it is now, as the body
+    pub fn route_fixture<P: AsRef<Path>>(&self, method: &str, path: &str, status: u16, file_rel_path: P) -> Box<&HttpStub> {
+        let body = AtPath::from_path(&self.state.tmpdir).read_bytes(file_rel_path);
+        self.route(method, path, status, body)
+    }
+
+    /// returns every request received so far, in the order they were received
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    /// returns the requests received so far with the provided method and path.
+    /// as for routes, a path without a query string matches any query string
+    pub fn requests_to(&self, method: &str, path: &str) -> Vec<RecordedRequest> {
+        self.requests().into_iter().filter(|request| request.matches(method, path)).collect()
+    }
+
+    /// asserts that at least one request was received with the provided method and path
+    pub fn received(&self, method: &str, path: &str) -> Box<&HttpStub> {
+        if self.requests_to(method, path).is_empty() {
+            panic!("expected the http stub to have received {} {}, but it received:\n{}",
+                   method, path, self.describe_requests());
+        }
+        Box::new(self)
+    }
+
+    /// asserts that exactly the provided number of requests were received with the provided method and path
+    pub fn received_times(&self, method: &str, path: &str, times: usize) -> Box<&HttpStub> {
+        let received = self.requests_to(method, path).len();
+        if received != times {
+            panic!("expected the http stub to have received {} {} {} times, but it was received {} times. requests:\n{}",
+                   method, path, times, received, self.describe_requests());
+        }
+        Box::new(self)
+    }
+
+    /// asserts that no requests were received at all
+    pub fn received_nothing(&self) -> Box<&HttpStub> {
+        if !self.requests().is_empty() {
+            panic!("expected the http stub to have received no requests, but it received:\n{}",
+                   self.describe_requests());
+        }
+        Box::new(self)
+    }
+
+    fn describe_requests(&self) -> String {
+        let requests = self.requests();
+        if requests.is_empty() {
+            return String::from("  (none)");
+        }
+        requests.iter().map(|request| format!("  {} {}", request.method, request.path))
+                .collect::<Vec<String>>().join("\n")
+    }
+
+    // replaces the placeholder with the base url of the server, if it appears in the value
+    pub(crate) fn expand(&self, value: &OsStr) -> OsString {
+        match value.to_str() {
+            Some(s) if s.contains(HTTP_STUB_PLACEHOLDER) => OsString::from(s.replace(HTTP_STUB_PLACEHOLDER, &self.url())),
+            _ => OsString::from(value),
+        }
+    }
+}
+
+/// The http stub server of a scene, which is stopped when dropped
+pub struct HttpStubServer {
+    pub stub: HttpStub,
+}
+
+impl HttpStubServer {
+    /// starts a server on a free port of the loopback interface. Fixtures for routes
+    /// are read from the provided directory
+    pub fn start(tmpdir: &Path) -> HttpStubServer {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).expect("tried to start the http stub but failed");
+        let stub = HttpStub {
+            state: Arc::new(StubState {
+                addr: listener.local_addr().expect("tried to start the http stub but failed"),
+                tmpdir: PathBuf::from(tmpdir),
+                routes: Mutex::new(Vec::new()),
+                requests: Mutex::new(Vec::new()),
+                shutdown: AtomicBool::new(false),
+            }),
+        };
+        log_info("http stub", stub.url());
+        let state = stub.state.clone();
+        // the thread isn't joined, as holding its handle would keep the scene from being unwind safe
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if state.shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let state = state.clone();
+                    // connections are independent, so that a client holding one open doesn't block the others
+                    thread::spawn(move || {
+                        if let Err(e) = serve(&state, stream) {
+                            log_info("http stub", format!("failed to serve a request: {}", e));
+                        }
+                    });
+                }
+            }
+        });
+        HttpStubServer {
+            stub,
+        }
+    }
+}
+
+impl Drop for HttpStubServer {
+    fn drop(&mut self) {
+        self.stub.state.shutdown.store(true, Ordering::SeqCst);
+        // wakes the listener so it sees the shutdown
+        let _ = TcpStream::connect(self.stub.state.addr);
+    }
+}
+
+// reads a single request from the connection, records it, and answers it.
+// Every response closes the connection, so there's no need to support keep-alive
+fn serve(state: &StubState, stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(CONNECTION_TIMEOUT_SECS)))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line)? == 0 {
+        // e.g. the wake-up connection made on shutdown
+        return Ok(());
+    }
+    let mut parts = request_line.trim_end().splitn(3, ' ');
+    let method = String::from(parts.next().unwrap_or(""));
+    let path = String::from(parts.next().unwrap_or(""));
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(colon) = line.find(':') {
+            headers.push((String::from(line[..colon].trim()), String::from(line[colon + 1..].trim())));
+        }
+    }
+    let mut request = RecordedRequest {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+
+    if request.header("Expect").map(|value| value.eq_ignore_ascii_case("100-continue")).unwrap_or(false) {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    }
+    let chunked = request.header("Transfer-Encoding").map(|value| value.to_lowercase().contains("chunked")).unwrap_or(false);
+    request.body = if chunked {
+        read_chunked(&mut reader)?
+    } else {
+        let length = request.header("Content-Length").and_then(|value| value.parse().ok()).unwrap_or(0);
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        body
+    };
+
+    let (status, response_headers, body) = {
+        let routes = state.routes.lock().unwrap();
+        match routes.iter().rev().find(|route| request.matches(&route.method, &route.path)) {
+            Some(route) => (route.status, route.headers.clone(), route.body.clone()),
+            None => (NOT_STUBBED_STATUS, Vec::new(),
+                     format!("no route of the http stub matches {} {}\n", request.method, request.path).into_bytes()),
+        }
+    };
+    log_info("http stub", format!("{} {} -> {}", request.method, request.path, status));
+    // recorded before answering, so that the request is visible as soon as the client has its response
+    state.requests.lock().unwrap().push(request);
+
+    let mut response = format!("HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                               status, reason_phrase(status), body.len());
+    for (key, value) in response_headers {
+        response.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    response.push_str("\r\n");
+    writer.write_all(response.as_bytes())?;
+    writer.write_all(&body)?;
+    writer.flush()?;
+    let _ = writer.shutdown(Shutdown::Write);
+    Ok(())
+}
+
+fn read_chunked<R: BufRead>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let mut size_line = String::new();
+        reader.read_line(&mut size_line)?;
+        let size = usize::from_str_radix(size_line.trim().split(';').next().unwrap_or(""), 16).unwrap_or(0);
+        if size == 0 {
+            // skips any trailers, up to the blank line ending the body
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                    return Ok(body);
+                }
+            }
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        let mut crlf = String::new();
+        reader.read_line(&mut crlf)?;
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        409 => "Conflict",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Stub",
+    }
+}
@@ -0,0 +1,72 @@
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static LLVM_PROFILE_FILE: &str = "LLVM_PROFILE_FILE";
+// variables read by coverage tooling and sanitizer runtimes in the instrumented binary
+static INSTRUMENTATION_VARS: &[&str] = &[
+    "LLVM_PROFILE_FILE",
+    "GCOV_PREFIX",
+    "GCOV_PREFIX_STRIP",
+    "ASAN_OPTIONS",
+    "LSAN_OPTIONS",
+    "MSAN_OPTIONS",
+    "TSAN_OPTIONS",
+    "UBSAN_OPTIONS",
+    "HWASAN_OPTIONS",
+    "ASAN_SYMBOLIZER_PATH",
+    "MSAN_SYMBOLIZER_PATH",
+    "TSAN_SYMBOLIZER_PATH",
+    "UBSAN_SYMBOLIZER_PATH",
+    "LLVM_SYMBOLIZER_PATH",
+];
+static INSTRUMENTATION_VAR_PREFIXES: &[&str] = &["CARGO_LLVM_COV"];
+
+// numbers the profile files of commands spawned by this test process
+static PROFILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn is_instrumentation_var(key: &OsStr) -> bool {
+    match key.to_str() {
+        Some(key) => INSTRUMENTATION_VARS.contains(&key)
+                     || INSTRUMENTATION_VAR_PREFIXES.iter().any(|prefix| key.starts_with(prefix)),
+        None => false,
+    }
+}
+
+// makes the profile file path absolute, as commands run in a temporary directory which is
+// later deleted, and unique to the command, so that commands don't overwrite each other's profiles.
+// llvm expands %p in the path to the pid of the process writing it.
+fn unique_profile_file(path: &OsStr) -> OsString {
+    let path = Path::new(path);
+    let path = if path.is_absolute() {
+        PathBuf::from(path)
+    } else {
+        env::current_dir().map(|cwd| cwd.join(path)).unwrap_or(PathBuf::from(path))
+    };
+    let mut file_name = path.file_stem().map(OsString::from).unwrap_or(OsString::from("default"));
+    file_name.push(format!("-second_law-{}-{}", process::id(), PROFILE_COUNTER.fetch_add(1, Ordering::SeqCst)));
+    if !path.to_string_lossy().contains("%p") {
+        file_name.push("-%p");
+    }
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name).into_os_string()
+}
+
+/// returns the coverage and sanitizer variables set in the environment the tests are run in,
+/// with any llvm profile file made unique to a single command
+pub fn instrumentation_env() -> Vec<(OsString, OsString)> {
+    env::vars_os()
+        .filter(|(key, _)| is_instrumentation_var(key))
+        .map(|(key, val)| if key == LLVM_PROFILE_FILE {
+            let unique = unique_profile_file(&val);
+            (key, unique)
+        } else {
+            (key, val)
+        })
+        .collect()
+}
@@ -0,0 +1,241 @@
+extern crate regex;
+
+use std::io::Write;
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use self::regex::bytes::Regex;
+
+use super::cmdresult::CmdResult;
+use super::common::log_info;
+use super::process::send_signal;
+use super::running::Running;
+
+static DEFAULT_EXPECT_TIMEOUT_SECS: u64 = 10;
+static EXPECT_POLL_INTERVAL_MS: u64 = 10;
+static ALREADY_WAITED: &str = "this session has already been waited on";
+static STDIN_CLOSED: &str = "stdin of this session has already been closed";
+
+/// A single step of an interactive session, as recorded in its transcript
+#[derive(Clone, Debug, PartialEq)]
+pub enum Exchange {
+    /// input written to the command's stdin
+    Sent(Vec<u8>),
+    /// stdout output of the command consumed by an expectation
+    Received(Vec<u8>),
+}
+
+/// An InteractiveSession is a running command which can be fed input, have
+/// its output waited upon step by step, and be sent signals, for testing prompt-driven
+/// and REPL-style binaries, or graceful shutdown.
+/// every input sent and output consumed is recorded in a transcript which is displayed
+/// when an expectation fails.
+/// If the session is dropped without being waited on, the command is killed.
+pub struct InteractiveSession {
+    running: Running,
+    // offset in stdout up to which output has been consumed by expectations
+    consumed: usize,
+    expect_timeout: Duration,
+    transcript: Vec<Exchange>,
+    finished: bool,
+}
+
+impl InteractiveSession {
+    pub fn new(running: Running) -> InteractiveSession {
+        InteractiveSession {
+            running,
+            consumed: 0,
+            expect_timeout: Duration::from_secs(DEFAULT_EXPECT_TIMEOUT_SECS),
+            transcript: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// sets how long expect_line(...) waits for a matching line before failing
+    pub fn expect_timeout(&mut self, timeout: Duration) -> Box<&mut InteractiveSession> {
+        self.expect_timeout = timeout;
+        Box::new(self)
+    }
+
+    /// writes the input to the command's stdin
+    pub fn send<T: Into<Vec<u8>>>(&mut self, input: T) -> Box<&mut InteractiveSession> {
+        let input = input.into();
+        log_info("send", String::from_utf8_lossy(&input));
+        {
+            let stdin = self.running.stdin.as_mut().expect(STDIN_CLOSED);
+            stdin.write_all(&input)
+                 .and_then(|_| stdin.flush())
+                 .unwrap_or_else(|e| panic!("failed writing to stdin: {}", e));
+        }
+        self.transcript.push(Exchange::Sent(input));
+        Box::new(self)
+    }
+
+    /// writes the input followed by a newline to the command's stdin
+    pub fn send_line<T: AsRef<str>>(&mut self, line: T) -> Box<&mut InteractiveSession> {
+        self.send(format!("{}\n", line.as_ref()))
+    }
+
+    /// closes the command's stdin, signalling end of input.
+    /// in pty mode, the terminal's end-of-file character is sent instead
+    pub fn close_stdin(&mut self) -> Box<&mut InteractiveSession> {
+        if self.running.stdin.is_none() {
+            panic!("{}", STDIN_CLOSED);
+        }
+        self.running.close_stdin();
+        log_info("close_stdin", &self.running.comm_string);
+        Box::new(self)
+    }
+
+    /// waits until the command outputs a line on stdout equal to the provided one
+    /// (ignoring the line ending), consuming all output up to and including that line.
+    /// fails if no such line is output within the expect timeout.
+    pub fn expect_line<T: AsRef<str>>(&mut self, line: T) -> Box<&mut InteractiveSession> {
+        let expected = line.as_ref().as_bytes().to_vec();
+        let description = format!("a line equal to {:?}", line.as_ref());
+        let timeout = self.expect_timeout;
+        self.expect_with(&description, timeout, |pending| {
+            let mut line_start = 0;
+            for (i, byte) in pending.iter().enumerate() {
+                if *byte == b'\n' {
+                    let mut line = &pending[line_start..i];
+                    if line.last() == Some(&b'\r') {
+                        line = &line[..line.len() - 1];
+                    }
+                    if line == &expected[..] {
+                        return Some(i + 1);
+                    }
+                    line_start = i + 1;
+                }
+            }
+            None
+        });
+        Box::new(self)
+    }
+
+    /// waits until the command's stdout output matches the provided regular expression,
+    /// consuming all output up to the end of the match.
+    /// returns the matched text followed by each capture group (empty if a group did not participate).
+    /// fails if there is no match within the provided timeout.
+    pub fn expect_regex<T: AsRef<str>>(&mut self, pattern: T, timeout: Duration) -> Vec<String> {
+        let regex = Regex::new(pattern.as_ref())
+            .unwrap_or_else(|e| panic!("invalid regular expression {:?}: {}", pattern.as_ref(), e));
+        let description = format!("output matching /{}/", pattern.as_ref());
+        let mut groups = Vec::new();
+        self.expect_with(&description, timeout, |pending| {
+            regex.captures(pending).map(|captures| {
+                groups = captures.iter()
+                                 .map(|group| group.map_or(String::new(), |g| String::from_utf8_lossy(g.as_bytes()).into_owned()))
+                                 .collect();
+                captures.get(0).unwrap().end()
+            })
+        });
+        groups
+    }
+
+    /// returns the process id of the command
+    pub fn pid(&self) -> u32 {
+        self.running.child.id()
+    }
+
+    /// sends the signal (e.g. libc::SIGINT) to the command immediately.
+    /// Only supported on unix platforms.
+    pub fn signal(&mut self, signal: i32) -> Box<&mut InteractiveSession> {
+        log_info("signal", format!("{} to {}", signal, self.running.comm_string));
+        if !send_signal(self.pid(), signal, &self.running.reaped) {
+            panic!("could not send signal {}, the command has already exited and been waited on", signal);
+        }
+        Box::new(self)
+    }
+
+    /// sends the signal to the command once the provided duration has elapsed,
+    /// unless it has exited and been waited on by then. Doesn't block.
+    /// Only supported on unix platforms.
+    pub fn signal_after(&mut self, signal: i32, delay: Duration) -> Box<&mut InteractiveSession> {
+        log_info("signal_after", format!("{} to {} in {:?}", signal, self.running.comm_string, delay));
+        let pid = self.pid();
+        let reaped = self.running.reaped.clone();
+        thread::spawn(move || {
+            sleep(delay);
+            send_signal(pid, signal, &reaped);
+        });
+        Box::new(self)
+    }
+
+    /// returns every input sent and output consumed so far
+    pub fn transcript(&self) -> &[Exchange] {
+        &self.transcript
+    }
+
+    /// renders the transcript for display, with sent input prefixed by '>'
+    /// and received output prefixed by '<'
+    pub fn transcript_string(&self) -> String {
+        let mut rendered = String::new();
+        for exchange in self.transcript.iter() {
+            let (prefix, bytes) = match *exchange {
+                Exchange::Sent(ref bytes) => ("> ", bytes),
+                Exchange::Received(ref bytes) => ("< ", bytes),
+            };
+            rendered.push_str(prefix);
+            rendered.push_str(&String::from_utf8_lossy(bytes).escape_debug().to_string());
+            rendered.push('\n');
+        }
+        rendered
+    }
+
+    /// closes stdin if still open, waits for the command to complete
+    /// and returns a command result holding its complete output.
+    pub fn wait(&mut self) -> CmdResult {
+        if self.finished {
+            panic!("{}", ALREADY_WAITED);
+        }
+        self.finished = true;
+        let result = self.running.finish();
+        if result.stdout_raw.len() > self.consumed {
+            self.transcript.push(Exchange::Received(result.stdout_raw[self.consumed..].to_vec()));
+            self.consumed = result.stdout_raw.len();
+        }
+        result
+    }
+
+    // polls stdout output not yet consumed until the matcher returns the offset
+    // (relative to that output) up to which it should be consumed.
+    fn expect_with<F>(&mut self, description: &str, timeout: Duration, mut matcher: F)
+        where F: FnMut(&[u8]) -> Option<usize>
+    {
+        if self.finished {
+            panic!("{}", ALREADY_WAITED);
+        }
+        log_info("expect", description);
+        let deadline = Instant::now() + timeout;
+        loop {
+            // check for closure before reading, so that output written just before it is not missed
+            let closed = self.running.stdout.is_closed();
+            let pending = self.running.stdout.contents_from(self.consumed);
+            if let Some(end) = matcher(&pending) {
+                self.transcript.push(Exchange::Received(pending[..end].to_vec()));
+                self.consumed += end;
+                return;
+            }
+            if closed || Instant::now() >= deadline {
+                panic!("expected {}, but {}.\nunconsumed stdout: {:?}\ntranscript:\n{}",
+                       description,
+                       if closed { "stdout was closed" } else { "timed out waiting" },
+                       String::from_utf8_lossy(&pending),
+                       self.transcript_string());
+            }
+            sleep(Duration::from_millis(EXPECT_POLL_INTERVAL_MS));
+        }
+    }
+}
+
+impl Drop for InteractiveSession {
+    fn drop(&mut self) {
+        if let Some(ref repro) = self.running.repro {
+            repro.write_if_panicking();
+        }
+        if !self.finished {
+            self.running.kill();
+        }
+    }
+}
@@ -5,11 +5,31 @@ mod ucommand;
 mod scene;
 mod common;
 mod settings;
+mod capture;
+mod process;
+mod pty;
+mod running;
+mod interactive;
+mod pipeline;
+mod batch;
+mod service;
+mod repro;
+mod instrumentation;
+#[cfg(feature = "http-stub")]
+mod http_stub;
 
 pub use atpath::AtPath;
-pub use ucommand::UCommand;
+pub use ucommand::{UCommand, FdSource};
 pub use scene::Scene;
 pub use cmdresult::CmdResult;
+pub use interactive::{InteractiveSession, Exchange};
+pub use pipeline::{Pipeline, PipelineResult};
+pub use batch::{Batch, BatchResult};
+pub use service::{Service, Readiness};
+pub use process::{ResourceUsage, LeftoverProcess};
+pub use capture::{OutputChunk, Stream};
+#[cfg(feature = "http-stub")]
+pub use http_stub::{HttpStub, RecordedRequest, HTTP_STUB_PLACEHOLDER};
 
 
 #[macro_export]
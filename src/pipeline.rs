@@ -0,0 +1,102 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::cmdresult::CmdResult;
+use super::common::log_info;
+use super::repro::Repro;
+use super::ucommand::UCommand;
+
+static EMPTY_PIPELINE: &str = "a pipeline must have at least one command";
+
+/// A Pipeline runs several UCommands of the same scene concurrently, with the stdout
+/// of each connected to the stdin of the next by an OS pipe, like `cmd1 | cmd2 | cmd3` in a shell.
+/// this streams data between the stages, so that behavior such as a stage exiting
+/// before consuming all of its input (and the SIGPIPE that follows upstream) is exercised.
+pub struct Pipeline {
+    stages: Vec<UCommand>,
+}
+
+/// The outputs of every stage of a pipeline
+pub struct PipelineResult {
+    /// the result of each stage, in order. The stdout of every
+    /// stage other than the last is consumed by the next stage, so is always empty
+    pub stages: Vec<CmdResult>,
+}
+
+impl Pipeline {
+    pub fn new(stages: Vec<UCommand>) -> Pipeline {
+        if stages.is_empty() {
+            panic!("{}", EMPTY_PIPELINE);
+        }
+        if !stages.iter().all(|stage| Arc::ptr_eq(stage.settings(), stages[0].settings())) {
+            panic!("every command of a pipeline must be created by the same scene");
+        }
+        Pipeline { stages }
+    }
+
+    /// appends another command to the end of the pipeline
+    pub fn pipe(&mut self, next: UCommand) -> Box<&mut Pipeline> {
+        if !Arc::ptr_eq(next.settings(), self.stages[0].settings()) {
+            panic!("every command of a pipeline must be created by the same scene");
+        }
+        self.stages.push(next);
+        Box::new(self)
+    }
+
+    /// Spawns every stage, feeds the first stage's stdin if any, waits for
+    /// every stage to complete, and returns their results.
+    pub fn run(&mut self) -> PipelineResult {
+        let comm_strings: Vec<&str> = self.stages.iter().map(|stage| stage.comm_string()).collect();
+        log_info("run pipeline", comm_strings.join(" | "));
+        let repro = Arc::new(self.repro(comm_strings.join(" | ")));
+        let last_index = self.stages.len() - 1;
+        let mut previous_stdout = None;
+        let mut running = Vec::new();
+        for (i, stage) in self.stages.iter_mut().enumerate() {
+            stage.set_repro(repro.clone());
+            let (stage_running, stage_stdout) = stage.start_stage(previous_stdout.take(), i == last_index);
+            running.push(stage_running);
+            previous_stdout = stage_stdout;
+        }
+        PipelineResult {
+            stages: running.iter_mut().map(|stage| stage.finish()).collect()
+        }
+    }
+
+    // a script running every stage, each in a subshell of its own, piped together as when run
+    fn repro(&self, comm_string: String) -> Repro {
+        let steps: Vec<String> = self.stages.iter().enumerate()
+            .map(|(i, stage)| format!("(\n{})", stage.repro_steps(i > 0)))
+            .collect();
+        let first = &self.stages[0];
+        Repro::new(comm_string, PathBuf::from(first.settings().tmpd.path()),
+                   format!("{}\n", steps.join(" |\n")), first.stdin_bytes().cloned())
+    }
+
+    /// Runs the pipeline and asserts that every stage succeeded
+    pub fn succeeds(&mut self) -> PipelineResult {
+        let result = self.run();
+        result.success();
+        result
+    }
+}
+
+impl PipelineResult {
+    /// returns the result of the stage at the provided index
+    pub fn stage(&self, index: usize) -> &CmdResult {
+        &self.stages[index]
+    }
+
+    /// returns the result of the last stage, whose stdout is the output of the pipeline
+    pub fn last(&self) -> &CmdResult {
+        self.stages.last().expect(EMPTY_PIPELINE)
+    }
+
+    /// asserts that every stage resulted in a success (zero) status code
+    pub fn success(&self) -> Box<&PipelineResult> {
+        for (i, stage) in self.stages.iter().enumerate() {
+            assert!(stage.success, "expected every stage to succeed, but stage {} ended with {}", i, stage.status);
+        }
+        Box::new(self)
+    }
+}
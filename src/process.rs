@@ -0,0 +1,391 @@
+#[cfg(unix)]
+extern crate libc;
+
+use std::process::{Child, Command, ExitStatus};
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+static WAIT_POLL_INTERVAL_MS: u64 = 10;
+
+/// makes the command, once spawned, the leader of a new process group,
+/// so that it can be killed along with everything it spawns.
+#[cfg(unix)]
+pub fn own_process_group(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    cmd.process_group(0);
+}
+
+#[cfg(not(unix))]
+pub fn own_process_group(_cmd: &mut Command) {}
+
+/// closes file descriptor 0 of the command, once spawned, before its binary is executed
+#[cfg(unix)]
+pub fn close_stdin_on_exec(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+
+    unsafe {
+        cmd.pre_exec(|| {
+            libc::close(0);
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub fn close_stdin_on_exec(_cmd: &mut Command) {
+    panic!("running a command with stdin closed is only supported on unix platforms");
+}
+
+/// makes each source file descriptor of this process available to the command, once spawned,
+/// at the paired target descriptor number. The source descriptors must remain open until it is spawned.
+#[cfg(unix)]
+pub fn pass_fds(cmd: &mut Command, fds: Vec<(i32, i32)>) {
+    use std::os::unix::process::CommandExt;
+
+    let above_targets = fds.iter().map(|&(_, target)| target).max().unwrap_or(0) + 1;
+    // allocated here, as allocating after fork can deadlock on a lock held by another thread
+    let mut moved = fds.clone();
+    unsafe {
+        cmd.pre_exec(move || {
+            // first move every source above the targets, so that placing one
+            // can't clobber another source which happens to use a target's number
+            for (&(source, _), moved) in fds.iter().zip(moved.iter_mut()) {
+                let above = libc::fcntl(source, libc::F_DUPFD_CLOEXEC, above_targets);
+                if above == -1 {
+                    return Err(::std::io::Error::last_os_error());
+                }
+                moved.0 = above;
+            }
+            for &(above, target) in moved.iter() {
+                if libc::dup2(above, target) == -1 {
+                    return Err(::std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// A resource of a process which can be limited through setrlimit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResourceLimit {
+    /// size of the virtual address space in bytes (RLIMIT_AS)
+    Memory,
+    /// one more than the highest file descriptor number which can be opened (RLIMIT_NOFILE)
+    OpenFiles,
+    /// cpu time in seconds (RLIMIT_CPU)
+    CpuTime,
+    /// size in bytes of the largest file which can be written (RLIMIT_FSIZE)
+    FileSize,
+    /// number of processes of the user, including this one (RLIMIT_NPROC)
+    Processes,
+}
+
+/// applies the resource limits to the command, once spawned, before its binary is executed
+#[cfg(unix)]
+pub fn set_resource_limits(cmd: &mut Command, limits: &[(ResourceLimit, u64)]) {
+    use std::os::unix::process::CommandExt;
+
+    let limits = limits.to_vec();
+    unsafe {
+        cmd.pre_exec(move || {
+            for &(resource, value) in limits.iter() {
+                let resource = match resource {
+                    ResourceLimit::Memory => libc::RLIMIT_AS,
+                    ResourceLimit::OpenFiles => libc::RLIMIT_NOFILE,
+                    ResourceLimit::CpuTime => libc::RLIMIT_CPU,
+                    ResourceLimit::FileSize => libc::RLIMIT_FSIZE,
+                    ResourceLimit::Processes => libc::RLIMIT_NPROC,
+                };
+                let limit = libc::rlimit { rlim_cur: value as libc::rlim_t, rlim_max: value as libc::rlim_t };
+                if libc::setrlimit(resource, &limit) == -1 {
+                    return Err(::std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub fn set_resource_limits(_cmd: &mut Command, _limits: &[(ResourceLimit, u64)]) {
+    panic!("resource limits are only supported on unix platforms");
+}
+
+/// kills every process remaining in the process group led by the provided pid.
+/// on platforms without process groups this is a no-op.
+#[cfg(unix)]
+pub fn kill_process_group(leader_pid: u32) {
+    unsafe {
+        libc::killpg(leader_pid as libc::pid_t, libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn kill_process_group(_leader_pid: u32) {}
+
+/// What to do about processes a command leaves running after it exits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Leftovers {
+    Ignore,
+    /// record any left in its process group
+    Track,
+    /// record, then kill, any left in its process group
+    Kill,
+}
+
+/// A process which remained in a command's process group after the command exited
+#[derive(Clone, Debug, PartialEq)]
+pub struct LeftoverProcess {
+    pub pid: u32,
+    /// the name of the process's executable, as reported by the OS
+    pub command: String,
+    /// whether it has exited, but not yet been reaped by its parent
+    pub zombie: bool,
+}
+
+// how long to allow descendants which are exiting along with a command to finish doing so
+static LEFTOVER_GRACE_MS: u64 = 100;
+
+/// lists the processes in the process group led by the provided pid, along with their parent pids
+#[cfg(target_os = "linux")]
+fn process_group_members(leader_pid: u32) -> Vec<(LeftoverProcess, u32)> {
+    use std::fs;
+
+    let mut members = Vec::new();
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return members,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let pid = match entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        // the process may exit while being read
+        let stat = match fs::read_to_string(entry.path().join("stat")) {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+        // "pid (comm) state ppid pgrp ...", where comm may itself contain spaces or parentheses
+        let (open, close) = match (stat.find('('), stat.rfind(')')) {
+            (Some(open), Some(close)) if open < close => (open, close),
+            _ => continue,
+        };
+        let fields: Vec<&str> = stat[close + 1..].split_whitespace().collect();
+        if fields.len() > 2 && fields[2].parse::<u32>().ok() == Some(leader_pid) {
+            members.push((LeftoverProcess {
+                pid,
+                command: String::from(&stat[open + 1..close]),
+                zombie: fields[0] == "Z",
+            }, fields[1].parse::<u32>().unwrap_or(0)));
+        }
+    }
+    members
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn process_group_members(leader_pid: u32) -> Vec<(LeftoverProcess, u32)> {
+    let output = match Command::new("ps").args(&["-A", "-o", "pid=,ppid=,pgid=,stat=,comm="]).output() {
+        Ok(output) => output,
+        Err(_) => return Vec::new(),
+    };
+    String::from_utf8_lossy(&output.stdout).lines().filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 5 || fields[2].parse::<u32>().ok() != Some(leader_pid) {
+            return None;
+        }
+        fields[0].parse::<u32>().ok().map(|pid| (LeftoverProcess {
+            pid: pid,
+            command: fields[4..].join(" "),
+            zombie: fields[3].starts_with('Z'),
+        }, fields[1].parse::<u32>().unwrap_or(0)))
+    }).collect()
+}
+
+#[cfg(not(unix))]
+fn process_group_members(_leader_pid: u32) -> Vec<(LeftoverProcess, u32)> {
+    Vec::new()
+}
+
+/// returns the processes remaining in the process group led by the provided pid,
+/// once the leader has been reaped, allowing a moment for any already exiting to finish.
+/// descendants which moved to another process group or session aren't found.
+/// zombies are only included while their parent is in the group: an orphaned zombie is
+/// left to init, which in containers without an init system may never reap it.
+pub fn leftover_processes(leader_pid: u32) -> Vec<LeftoverProcess> {
+    let grace_deadline = Instant::now() + Duration::from_millis(LEFTOVER_GRACE_MS);
+    loop {
+        let members = process_group_members(leader_pid);
+        let in_group: Vec<u32> = members.iter().map(|(member, _)| member.pid).collect();
+        let members: Vec<LeftoverProcess> = members.into_iter()
+            .filter(|&(ref member, parent)| !member.zombie || in_group.contains(&parent))
+            .map(|(member, _)| member)
+            .collect();
+        if members.is_empty() || Instant::now() >= grace_deadline {
+            return members;
+        }
+        sleep(Duration::from_millis(WAIT_POLL_INTERVAL_MS));
+    }
+}
+
+/// kills the child and (if it was spawned with own_process_group)
+/// every process it has spawned.
+pub fn kill_tree(child: &mut Child) {
+    kill_process_group(child.id());
+    let _ = child.kill();
+}
+
+/// blocks until the child has exited, without reaping it,
+/// so that its pid can't be reused in the meantime.
+#[cfg(unix)]
+fn wait_exited(child: &Child) {
+    loop {
+        let mut info: libc::siginfo_t = unsafe { ::std::mem::zeroed() };
+        let ret = unsafe {
+            libc::waitid(libc::P_PID, child.id() as libc::id_t, &mut info, libc::WEXITED | libc::WNOWAIT)
+        };
+        if ret == 0 || ::std::io::Error::last_os_error().kind() != ::std::io::ErrorKind::Interrupted {
+            return;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn wait_exited(_child: &Child) {}
+
+/// returns whether the child has exited, without blocking or reaping it.
+/// always false on platforms where that can't be checked without reaping.
+#[cfg(unix)]
+pub fn has_exited(child: &Child) -> bool {
+    let mut info: libc::siginfo_t = unsafe { ::std::mem::zeroed() };
+    let ret = unsafe {
+        libc::waitid(libc::P_PID, child.id() as libc::id_t, &mut info,
+                     libc::WEXITED | libc::WNOHANG | libc::WNOWAIT)
+    };
+    // with WNOHANG, si_pid is left zeroed if the child hasn't exited yet
+    ret == 0 && unsafe { info.si_pid() } != 0
+}
+
+#[cfg(not(unix))]
+pub fn has_exited(_child: &Child) -> bool {
+    false
+}
+
+/// Resources consumed by a command, as reported by the OS when it was reaped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResourceUsage {
+    /// cpu time spent executing in user mode
+    pub user_time: Duration,
+    /// cpu time spent executing in kernel mode
+    pub system_time: Duration,
+    /// peak resident set size, in bytes
+    pub max_rss: u64,
+}
+
+#[cfg(unix)]
+fn timeval_to_duration(tv: libc::timeval) -> Duration {
+    Duration::new(tv.tv_sec as u64, (tv.tv_usec as u32) * 1000)
+}
+
+/// reaps the child if it has exited, or blocks until it does if requested,
+/// returning its status along with its resource usage.
+#[cfg(unix)]
+fn reap(child: &mut Child, block: bool) -> Option<(ExitStatus, Option<ResourceUsage>)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let pid = child.id() as libc::pid_t;
+    let mut status: libc::c_int = 0;
+    let mut usage: libc::rusage = unsafe { ::std::mem::zeroed() };
+    loop {
+        let ret = unsafe {
+            libc::wait4(pid, &mut status, if block { 0 } else { libc::WNOHANG }, &mut usage)
+        };
+        if ret == pid {
+            // ru_maxrss is in bytes on macOS, but in kilobytes elsewhere
+            let max_rss_units = if cfg!(target_os = "macos") { 1 } else { 1024 };
+            return Some((ExitStatus::from_raw(status), Some(ResourceUsage {
+                user_time: timeval_to_duration(usage.ru_utime),
+                system_time: timeval_to_duration(usage.ru_stime),
+                max_rss: usage.ru_maxrss as u64 * max_rss_units,
+            })));
+        }
+        if ret == 0 {
+            return None;
+        }
+        let err = ::std::io::Error::last_os_error();
+        if err.kind() != ::std::io::ErrorKind::Interrupted {
+            panic!("failed to wait on child process: {}", err);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn reap(child: &mut Child, block: bool) -> Option<(ExitStatus, Option<ResourceUsage>)> {
+    if block {
+        Some((child.wait().expect("failed to wait on child process"), None))
+    } else {
+        child.try_wait().expect("failed to wait on child process").map(|status| (status, None))
+    }
+}
+
+/// What became of a child that was waited upon
+pub struct Reaped {
+    pub status: ExitStatus,
+    /// whether the deadline passed, so the child was killed
+    pub timed_out: bool,
+    /// None on platforms which don't report resource usage
+    pub usage: Option<ResourceUsage>,
+}
+
+/// waits for the child to exit. If a deadline is provided and passes first,
+/// the child's process tree is killed.
+/// the child is only reaped while holding the reaped lock, which is then set,
+/// so that signals sent while holding that lock can't reach a reused pid.
+pub fn wait_until(child: &mut Child, deadline: Option<Instant>, reaped: &Mutex<bool>) -> Reaped {
+    let deadline = match deadline {
+        None => {
+            wait_exited(child);
+            let mut reaped = reaped.lock().unwrap();
+            let (status, usage) = reap(child, true).unwrap();
+            *reaped = true;
+            return Reaped { status, timed_out: false, usage };
+        },
+        Some(deadline) => deadline,
+    };
+    loop {
+        {
+            let mut reaped = reaped.lock().unwrap();
+            if let Some((status, usage)) = reap(child, false) {
+                *reaped = true;
+                return Reaped { status, timed_out: false, usage };
+            }
+            if Instant::now() >= deadline {
+                kill_tree(child);
+                let (status, usage) = reap(child, true).unwrap();
+                *reaped = true;
+                return Reaped { status, timed_out: true, usage };
+            }
+        }
+        sleep(Duration::from_millis(WAIT_POLL_INTERVAL_MS));
+    }
+}
+
+/// sends the signal to the process with the provided pid, unless the reaped lock
+/// shows it has already been reaped. returns whether the signal was sent.
+#[cfg(unix)]
+pub fn send_signal(pid: u32, signal: i32, reaped: &Mutex<bool>) -> bool {
+    let reaped = reaped.lock().unwrap();
+    if *reaped {
+        return false;
+    }
+    unsafe {
+        libc::kill(pid as libc::pid_t, signal) == 0
+    }
+}
+
+#[cfg(not(unix))]
+pub fn send_signal(_pid: u32, _signal: i32, _reaped: &Mutex<bool>) -> bool {
+    panic!("sending signals is only supported on unix platforms");
+}
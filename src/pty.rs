@@ -0,0 +1,83 @@
+#[cfg(unix)]
+extern crate libc;
+
+use std::fs::File;
+use std::process::Command;
+
+pub static DEFAULT_PTY_COLS: u16 = 80;
+pub static DEFAULT_PTY_ROWS: u16 = 24;
+// the terminal's end-of-file character (^D), as stdin of a pty can't be closed
+pub static EOT: u8 = 0x04;
+
+/// A pseudo-terminal pair. The slave side becomes the controlling terminal,
+/// and the stdin, stdout and stderr, of the command it is attached to;
+/// the master side is used to write its input and read its output.
+pub struct Pty {
+    pub master: File,
+    slave: File,
+}
+
+impl Pty {
+    #[cfg(unix)]
+    pub fn open(cols: u16, rows: u16) -> Pty {
+        use std::os::unix::io::FromRawFd;
+        use std::ptr;
+
+        let mut master: libc::c_int = -1;
+        let mut slave: libc::c_int = -1;
+        let mut size = libc::winsize { ws_col: cols, ws_row: rows, ws_xpixel: 0, ws_ypixel: 0 };
+        // the size is taken as *mut on some platforms and *const on others
+        let ret = unsafe {
+            libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null_mut(), &mut size as *mut libc::winsize as _)
+        };
+        if ret != 0 {
+            panic!("tried to allocate a pseudo-terminal but failed: {}", ::std::io::Error::last_os_error());
+        }
+        // otherwise commands spawned concurrently (by other tests, batches or services) inherit
+        // the pair, and the master doesn't see end of file until they exit as well.
+        // the slave is still attached to the command, as dup2 onto its stdio clears the flag
+        set_cloexec(master);
+        set_cloexec(slave);
+        unsafe {
+            Pty { master: File::from_raw_fd(master), slave: File::from_raw_fd(slave) }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn open(_cols: u16, _rows: u16) -> Pty {
+        panic!("pty mode is only supported on unix platforms");
+    }
+
+    /// makes the slave side the stdin, stdout, stderr and controlling terminal of the command
+    #[cfg(unix)]
+    pub fn attach(&self, cmd: &mut Command) {
+        use std::os::unix::process::CommandExt;
+
+        cmd.stdin(self.slave.try_clone().expect("failed to duplicate pty"))
+           .stdout(self.slave.try_clone().expect("failed to duplicate pty"))
+           .stderr(self.slave.try_clone().expect("failed to duplicate pty"));
+        unsafe {
+            cmd.pre_exec(|| {
+                // a new session also makes the command the leader of a new process group
+                if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                    return Err(::std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn attach(&self, _cmd: &mut Command) {}
+}
+
+#[cfg(unix)]
+fn set_cloexec(fd: libc::c_int) {
+    let ret = unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags == -1 { -1 } else { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) }
+    };
+    if ret == -1 {
+        panic!("tried to set close-on-exec on a pseudo-terminal but failed: {}", ::std::io::Error::last_os_error());
+    }
+}
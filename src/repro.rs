@@ -0,0 +1,195 @@
+extern crate tempdir;
+
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::Deref;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use self::tempdir::TempDir;
+
+use super::common::{log_info, recursive_copy_skipping_special};
+
+static REPRO_DIR_PREFIX: &str = "second_law-repro";
+// set by the script to the copy of the scene's temporary directory
+static SCENE_VAR: &str = "\"$scene\"";
+
+/// A shell script which reproduces a command outside of the test harness,
+/// written out (along with a copy of the scene's temporary directory) if a test
+/// fails while the command's result, or interactive session, is in scope.
+pub struct Repro {
+    comm_string: String,
+    tmpdir: PathBuf,
+    // the body of the script, run from the script's directory
+    body: String,
+    // input for the command, saved alongside the script
+    stdin: Option<Vec<u8>>,
+    written: Mutex<bool>,
+}
+
+impl Repro {
+    pub fn new(comm_string: String, tmpdir: PathBuf, body: String, stdin: Option<Vec<u8>>) -> Repro {
+        Repro {
+            comm_string,
+            tmpdir,
+            body,
+            stdin,
+            written: Mutex::new(false),
+        }
+    }
+
+    /// writes out the script if the current thread is panicking, e.g. on a failed assertion,
+    /// unless it has already been written
+    pub fn write_if_panicking(&self) {
+        if thread::panicking() {
+            self.write_once();
+        }
+    }
+
+    /// writes out the script, unless it has already been written (or dismissed),
+    /// and returns its path if it was written now
+    pub fn write_once(&self) -> Option<PathBuf> {
+        let mut written = match self.written.lock() {
+            Ok(written) => written,
+            Err(_) => return None,
+        };
+        if *written {
+            return None;
+        }
+        *written = true;
+        // panicking again while unwinding would abort the test run, so failures are only logged
+        match self.write() {
+            Ok(script) => {
+                log_info("repro", script.to_string_lossy());
+                Some(script)
+            },
+            Err(e) => {
+                log_info("repro", format!("failed to write a repro script for {}: {}", self.comm_string, e));
+                None
+            },
+        }
+    }
+
+    /// prevents the script from being written, for a command known not to have failed
+    pub fn dismiss(&self) {
+        if let Ok(mut written) = self.written.lock() {
+            *written = true;
+        }
+    }
+
+    fn write(&self) -> ::std::io::Result<PathBuf> {
+        let dir = TempDir::new_in(env::temp_dir(), REPRO_DIR_PREFIX)?.into_path();
+        let scene = dir.join("scene");
+        fs::create_dir(&scene)?;
+        recursive_copy_skipping_special(&self.tmpdir, &scene)?;
+        if let Some(ref stdin) = self.stdin {
+            File::create(dir.join("stdin"))?.write_all(stdin)?;
+        }
+        let script = dir.join("repro.sh");
+        File::create(&script)?.write_all(format!(
+            "#!/usr/bin/env bash\n\
+             # reproduces: {}\n\
+             # scene/ is a copy of the scene's temporary directory as it was when the test failed,\n\
+             # so it includes any changes the command made to it\n\
+             here=\"$(cd \"$(dirname \"$0\")\" && pwd)\"\n\
+             scene=\"$here/scene\"\n\
+             {}",
+            self.comm_string.replace('\n', " "), self.body).as_bytes())?;
+        #[cfg(unix)]
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755))?;
+        Ok(script)
+    }
+}
+
+/// Writes out a repro script if dropped while the thread is panicking, so that a value
+/// holding one, such as a CmdResult, needn't implement Drop itself, which would prevent
+/// moving its fields out of it
+pub struct ReproGuard(Arc<Repro>);
+
+impl ReproGuard {
+    pub fn new(repro: Arc<Repro>) -> ReproGuard {
+        ReproGuard(repro)
+    }
+}
+
+impl Deref for ReproGuard {
+    type Target = Repro;
+
+    fn deref(&self) -> &Repro {
+        &self.0
+    }
+}
+
+impl Drop for ReproGuard {
+    fn drop(&mut self) {
+        self.0.write_if_panicking();
+    }
+}
+
+#[cfg(unix)]
+fn os_bytes(s: &OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    s.as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn os_bytes(s: &OsStr) -> Vec<u8> {
+    s.to_string_lossy().into_owned().into_bytes()
+}
+
+fn is_plain(byte: u8) -> bool {
+    matches!(byte, b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9'
+                   | b'_' | b'@' | b'%' | b'+' | b'=' | b':' | b',' | b'.' | b'/' | b'-')
+}
+
+// quotes bytes as a single shell word. bytes which aren't printable ascii
+// are written as escapes within $'...', which bash understands
+fn quote_bytes(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return String::from("''");
+    }
+    if bytes.iter().all(|b| is_plain(*b)) {
+        return String::from_utf8_lossy(bytes).into_owned();
+    }
+    if bytes.iter().all(|b| *b >= 0x20 && *b < 0x7f) {
+        return format!("'{}'", String::from_utf8_lossy(bytes).replace('\'', "'\\''"));
+    }
+    let mut quoted = String::from("$'");
+    for byte in bytes {
+        match *byte {
+            b'\\' => quoted.push_str("\\\\"),
+            b'\'' => quoted.push_str("\\'"),
+            b'\n' => quoted.push_str("\\n"),
+            b'\t' => quoted.push_str("\\t"),
+            0x20..=0x7e => quoted.push(*byte as char),
+            _ => quoted.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// quotes the value as a single shell word, replacing any occurrence of the
+/// scene's temporary directory with the script's copy of it
+pub fn shell_word<S: AsRef<OsStr>>(value: S, tmpdir: &Path) -> String {
+    let bytes = os_bytes(value.as_ref());
+    let tmpdir = os_bytes(tmpdir.as_os_str());
+    let mut word = String::new();
+    let mut rest = &bytes[..];
+    while let Some(at) = rest.windows(tmpdir.len()).position(|window| window == &tmpdir[..]) {
+        if at > 0 {
+            word.push_str(&quote_bytes(&rest[..at]));
+        }
+        word.push_str(SCENE_VAR);
+        rest = &rest[at + tmpdir.len()..];
+    }
+    if !rest.is_empty() || word.is_empty() {
+        word.push_str(&quote_bytes(rest));
+    }
+    word
+}
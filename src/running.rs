@@ -0,0 +1,222 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::process::{Child, ChildStdin};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::capture::{feed, Capture, CombinedLog, Stream};
+use super::cmdresult::CmdResult;
+use super::common::log_info;
+use super::process::{kill_process_group, kill_tree, leftover_processes, wait_until, Leftovers};
+use super::pty::EOT;
+use super::repro::{Repro, ReproGuard};
+use super::settings::SceneSettings;
+
+// how long to keep reading output after a timed out process tree is killed
+static KILLED_OUTPUT_GRACE_MS: u64 = 100;
+
+/// The input side of a spawned command
+pub enum StdinWriter {
+    Pipe(ChildStdin),
+    /// the master side of the pseudo-terminal the command is attached to
+    Pty(File),
+}
+
+impl Write for StdinWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            StdinWriter::Pipe(ref mut pipe) => pipe.write(buf),
+            StdinWriter::Pty(ref mut master) => master.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            StdinWriter::Pipe(ref mut pipe) => pipe.flush(),
+            StdinWriter::Pty(ref mut master) => master.flush(),
+        }
+    }
+}
+
+/// A spawned command whose output streams are being captured.
+/// Once the command completes, its status and output are gathered into a CmdResult.
+pub struct Running {
+    pub child: Child,
+    pub stdin: Option<StdinWriter>,
+    pub stdout: Capture,
+    pub stderr: Capture,
+    pub deadline: Option<Instant>,
+    pub settings: Arc<SceneSettings>,
+    pub comm_string: String,
+    pub started: Instant,
+    pub combined: Option<CombinedLog>,
+    /// captures of additional file descriptors passed to the command, by number
+    pub fds: Vec<(i32, Capture)>,
+    /// a script reproducing the command, written out if a test fails while it's in scope
+    pub repro: Option<Arc<Repro>>,
+    /// what to do about processes left in the command's process group once it exits
+    pub leftovers: Leftovers,
+    /// set once the child has been reaped, and locked while doing so
+    pub reaped: Arc<Mutex<bool>>,
+    /// a handle to the master side of the command's pseudo-terminal, if any, held until the
+    /// command is reaped. Once its output has been read, closing every handle would hang up the
+    /// terminal, killing a command which closes its stdio before exiting with SIGHUP
+    pub terminal: Option<File>,
+}
+
+impl Running {
+    /// captures whichever of the child's output streams were piped.
+    /// if a stdout read limit is provided, stdout is closed once that many bytes have been read.
+    /// if combined is set, the order in which output arrives on each stream is also recorded.
+    pub fn new(mut child: Child, stdout_read_limit: Option<usize>, combined: bool, deadline: Option<Instant>, settings: Arc<SceneSettings>, comm_string: String) -> Running {
+        let started = Instant::now();
+        let combined = if combined { Some(CombinedLog::new(started)) } else { None };
+        Running {
+            stdin: child.stdin.take().map(StdinWriter::Pipe),
+            stdout: child.stdout.take()
+                               .map(|stdout| Capture::spawn_logged(stdout, stdout_read_limit,
+                                                                   combined.clone().map(|log| (log, Stream::Stdout))))
+                               .unwrap_or_else(Capture::closed),
+            stderr: child.stderr.take()
+                               .map(|stderr| Capture::spawn_logged(stderr, None,
+                                                                   combined.clone().map(|log| (log, Stream::Stderr))))
+                               .unwrap_or_else(Capture::closed),
+            combined,
+            fds: Vec::new(),
+            repro: None,
+            leftovers: Leftovers::Ignore,
+            started,
+            child,
+            deadline,
+            settings,
+            comm_string,
+            reaped: Arc::new(Mutex::new(false)),
+            terminal: None,
+        }
+    }
+
+    /// like new(...), but for a command attached to a pseudo-terminal,
+    /// in which case all of its terminal output is captured as stdout
+    pub fn new_pty(child: Child, master: File, combined: bool, deadline: Option<Instant>, settings: Arc<SceneSettings>, comm_string: String) -> Running {
+        let started = Instant::now();
+        let combined = if combined { Some(CombinedLog::new(started)) } else { None };
+        Running {
+            stdout: Capture::spawn_logged(master.try_clone().expect("failed to duplicate pty"), None,
+                                          combined.clone().map(|log| (log, Stream::Stdout))),
+            combined,
+            fds: Vec::new(),
+            repro: None,
+            leftovers: Leftovers::Ignore,
+            started,
+            terminal: Some(master.try_clone().expect("failed to duplicate pty")),
+            stdin: Some(StdinWriter::Pty(master)),
+            stderr: Capture::closed(),
+            child,
+            deadline,
+            settings,
+            comm_string,
+            reaped: Arc::new(Mutex::new(false)),
+        }
+    }
+
+    /// writes the input to stdin on a separate thread, then closes stdin
+    pub fn feed(&mut self, mut input: Vec<u8>) {
+        if let Some(StdinWriter::Pty(_)) = self.stdin {
+            // an end-of-file character only ends input at the start of a line,
+            // otherwise it first submits the partial line
+            if input.last().is_some_and(|last| *last != b'\n') {
+                input.push(EOT);
+            }
+            input.push(EOT);
+        }
+        feed(self.stdin.take().expect("stdin has already been closed"), input);
+    }
+
+    /// closes stdin. For a pseudo-terminal, whose stdin can't be closed
+    /// independently of its output, the end-of-file character is sent instead.
+    pub fn close_stdin(&mut self) {
+        if let Some(StdinWriter::Pty(mut master)) = self.stdin.take() {
+            let _ = master.write_all(&[EOT]);
+        }
+    }
+
+    /// kills the command and every process it has spawned, if it hasn't already been reaped
+    pub fn kill(&mut self) {
+        let mut reaped = self.reaped.lock().unwrap();
+        if !*reaped {
+            kill_tree(&mut self.child);
+            let _ = self.child.wait();
+            *reaped = true;
+        }
+    }
+
+    /// closes stdin, waits for the command to exit (killing it if the deadline passes first),
+    /// and returns its result.
+    pub fn finish(&mut self) -> CmdResult {
+        self.close_stdin();
+        let reaped = wait_until(&mut self.child, self.deadline, &self.reaped);
+        self.terminal = None;
+        let wall_time = self.started.elapsed();
+        let status = reaped.status;
+        let mut timed_out = reaped.timed_out;
+        // checked before waiting on the output streams, which leftover processes may be holding open
+        let leftover = if self.leftovers == Leftovers::Ignore {
+            None
+        } else {
+            let leftover = leftover_processes(self.child.id());
+            if !leftover.is_empty() {
+                log_info("leftover processes", format!("{:?}", leftover));
+                if self.leftovers == Leftovers::Kill {
+                    kill_process_group(self.child.id());
+                }
+            }
+            Some(leftover)
+        };
+        let leftovers_found = leftover.as_ref().is_some_and(|leftover| !leftover.is_empty());
+        let grace_deadline = Instant::now() + Duration::from_millis(KILLED_OUTPUT_GRACE_MS);
+        let streams_deadline = if timed_out {
+            Some(grace_deadline)
+        } else if leftovers_found {
+            // a leftover daemon may hold the streams open indefinitely, which would
+            // keep the test from reaching its assertion about leftovers
+            Some(self.deadline.map_or(grace_deadline, |deadline| deadline.min(grace_deadline)))
+        } else {
+            self.deadline
+        };
+        // the process may have exited while leaving descendants
+        // that hold its output streams open past the deadline
+        let all_closed = self.stdout.wait_closed(streams_deadline)
+                         && self.stderr.wait_closed(streams_deadline)
+                         && self.fds.iter().all(|(_, fd)| fd.wait_closed(streams_deadline));
+        if !all_closed {
+            if leftovers_found && !timed_out {
+                log_info("leftover processes", "holding the output open, returning the output so far");
+            } else {
+                kill_process_group(self.child.id());
+                timed_out = true;
+            }
+        }
+        if timed_out {
+            log_info("timed out", &self.comm_string);
+        }
+
+        let stdout_raw = self.stdout.contents();
+        let stderr_raw = self.stderr.contents();
+        CmdResult {
+            settings: self.settings.clone(),
+            success: status.success() && !timed_out,
+            status,
+            timed_out,
+            stdout: String::from_utf8_lossy(&stdout_raw).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr_raw).into_owned(),
+            stdout_raw,
+            stderr_raw,
+            wall_time,
+            usage: reaped.usage,
+            combined: self.combined.as_ref().map(|log| log.chunks()),
+            fds: self.fds.iter().map(|&(n, ref fd)| (n, fd.contents())).collect(),
+            repro: self.repro.clone().map(ReproGuard::new),
+            leftover_processes: leftover,
+        }
+    }
+}
@@ -5,12 +5,17 @@ use std::ffi::{OsStr,OsString};
 use std::fs::{self};
 use std::path::{Path,PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use self::tempdir::TempDir;
 
 use super::atpath::AtPath;
+use super::batch::{Batch, BatchResult};
+use super::service::{free_port, Readiness, Service};
 use super::ucommand::UCommand;
-use super::common::recursive_copy;
+use super::common::{log_info, os_display, recursive_copy};
 use super::settings::SceneSettings;
+#[cfg(feature = "http-stub")]
+use super::http_stub::{HttpStub, HttpStubServer};
 
 //#[macro_export]
 macro_rules! path_concat {
@@ -35,6 +40,9 @@ macro_rules! path_concat {
 
 static DEFAULT_FIXTURES_ROOT: &'static str = "tests/fixtures";
 static ROOT_CALLED_MAX_ONCE: &'static str = "the fixture root can only be set once. To add subdirectories in multiple steps, use .fixtures_subdir(:&Path)";
+// overrides cargo's runner for the target, with an empty value running commands directly
+static RUNNER_ENV_VAR: &str = "SECOND_LAW_RUNNER";
+static DEFAULT_SERVICE_READY_TIMEOUT_SECS: u64 = 10;
 static ALREADY_INSTANTIATED: &'static str = "configuration of a scene must be done before the first call to its .cmd() or .ucmd()";
 
 // why not lifetimes? design choices explanation at the end of this source
@@ -45,6 +53,14 @@ struct SceneBuilder {
     pub repo_fixtroot_subpath: Option<PathBuf>,
     pub subcmd_args: Option<Vec<OsString>>,
     pub multicall: Option<OsString>,
+    pub default_timeout: Option<Duration>,
+    pub inherited_env: Vec<OsString>,
+    pub env_changes: Vec<(OsString, Option<OsString>)>,
+    pub runner: Option<Vec<OsString>>,
+    pub forward_instrumentation_env: bool,
+    pub multicall_argv0: Option<OsString>,
+    #[cfg(feature = "http-stub")]
+    pub http_stub: bool,
 }
 
 /// An environment for running a single uutils test case, serves three functions:
@@ -67,7 +83,15 @@ impl Scene {
                 fixtroot_fixture_subpath: None,
                 repo_fixtroot_subpath: None,
                 subcmd_args: None,
-                multicall: None
+                multicall: None,
+                default_timeout: None,
+                inherited_env: Vec::new(),
+                env_changes: Vec::new(),
+                runner: None,
+                forward_instrumentation_env: true,
+                multicall_argv0: None,
+                #[cfg(feature = "http-stub")]
+                http_stub: false
             }),
             setting : None
         }
@@ -104,6 +128,21 @@ impl Scene {
         self
     }
 
+    /// like multicall(...), but rather than passing the utility name as the first argument,
+    /// invokes the binary through a link named after the utility (a symlink on unix, otherwise a hardlink),
+    /// so that it dispatches on argv[0] as it would when installed busybox-style.
+    /// The link is created in a private directory, rather than the scene's temporary directory.
+    pub fn multicall_argv0<S: AsRef<OsStr>>(&mut self, name : S) -> &Scene {
+        if Path::new(name.as_ref()).components().count() != 1 {
+            panic!("the name a multicall binary is invoked as can't contain a path separator");
+        }
+        self.fixtures_subdir(Path::new(name.as_ref()));
+        if let Some(ref mut builder) = self.builder {
+            builder.multicall_argv0 = Some(OsString::from(name.as_ref()));
+        }
+        self
+    }
+
     pub fn subcmd_arg<'a, S: AsRef<OsStr>>(&'a mut self, added_arg : S) -> &'a Scene {
         if let Some(ref mut builder) = self.builder {
             if let Some(ref mut current_value) = builder.subcmd_args {
@@ -138,13 +177,128 @@ impl Scene {
         self
     }
     
+    /// sets a timeout applied to every command created by this scene,
+    /// unless overridden by UCommand::timeout(...)
+    pub fn timeout(&mut self, timeout : Duration) -> &Scene {
+        if let Some(ref mut builder) = self.builder {
+            builder.default_timeout = Some(timeout);
+        } else {
+            panic!("{}", ALREADY_INSTANTIATED);
+        }
+        self
+    }
+
+    /// sets an environment variable for every command created by this scene
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key : K, val : V) -> &Scene {
+        if let Some(ref mut builder) = self.builder {
+            builder.env_changes.push((OsString::from(key.as_ref()), Some(OsString::from(val.as_ref()))));
+        } else {
+            panic!("{}", ALREADY_INSTANTIATED);
+        }
+        self
+    }
+
+    /// sets each of the provided environment variables for every command created by this scene
+    pub fn envs<I, K, V>(&mut self, vars : I) -> &Scene
+        where I: IntoIterator<Item = (K, V)>, K: AsRef<OsStr>, V: AsRef<OsStr>
+    {
+        for (key, val) in vars {
+            self.env(key, val);
+        }
+        self
+    }
+
+    /// removes an environment variable for every command created by this scene.
+    /// only meaningful for commands created by ucmd_keepenv() or cmd_keepenv()
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key : K) -> &Scene {
+        if let Some(ref mut builder) = self.builder {
+            builder.env_changes.push((OsString::from(key.as_ref()), None));
+        } else {
+            panic!("{}", ALREADY_INSTANTIATED);
+        }
+        self
+    }
+
+    /// copies the provided variables, where set, from the environment the tests are run in
+    /// to the environment of every command created by this scene, as UCommand::inherit_env(...)
+    pub fn inherit_env<'a, S: AsRef<OsStr>>(&'a mut self, keys : &[S]) -> &'a Scene {
+        if let Some(ref mut builder) = self.builder {
+            for key in keys.iter() {
+                builder.inherited_env.push(OsString::from(key.as_ref()));
+            }
+        } else {
+            panic!("{}", ALREADY_INSTANTIATED);
+        }
+        self
+    }
+
+    /// runs every command created by ucmd() under the provided runner command,
+    /// e.g. &["valgrind", "--error-exitcode=1"], to which the binary and its arguments are appended.
+    /// An empty runner runs commands directly.
+    /// Without this, the runner is read as a space-separated command from the SECOND_LAW_RUNNER
+    /// environment variable if set, or otherwise from cargo's CARGO_TARGET_<triple>_RUNNER
+    pub fn runner<'a, S: AsRef<OsStr>>(&'a mut self, runner : &[S]) -> &'a Scene {
+        if let Some(ref mut builder) = self.builder {
+            builder.runner = Some(runner.iter().map(|part| OsString::from(part.as_ref())).collect());
+        } else {
+            panic!("{}", ALREADY_INSTANTIATED);
+        }
+        self
+    }
+
+    /// by default, coverage and sanitizer variables (LLVM_PROFILE_FILE, CARGO_LLVM_COV*, ASAN_OPTIONS, etc.)
+    /// set in the environment the tests are run in are kept when a command's environment is cleared,
+    /// so that instrumented binaries still report to the tooling, with each command writing a separate
+    /// llvm profile. Passing false clears them along with the rest of the environment.
+    pub fn forward_instrumentation_env(&mut self, forward : bool) -> &Scene {
+        if let Some(ref mut builder) = self.builder {
+            builder.forward_instrumentation_env = forward;
+        } else {
+            panic!("{}", ALREADY_INSTANTIATED);
+        }
+        self
+    }
+
+    /// serves a local http server on 127.0.0.1 for the scene, with routes added through http_stub(),
+    /// for testing binaries which call an http api offline.
+    /// "{http_stub}" in the arguments or environment variables of a command, including those set
+    /// for every command by env(...), is replaced with its base url, e.g. "http://127.0.0.1:41234"
+    #[cfg(feature = "http-stub")]
+    pub fn serve_http_stub(&mut self) -> &Scene {
+        if let Some(ref mut builder) = self.builder {
+            builder.http_stub = true;
+        } else {
+            panic!("{}", ALREADY_INSTANTIATED);
+        }
+        self
+    }
+
+    /// returns the http stub served by serve_http_stub(), for adding routes
+    /// and asserting on the requests it received
+    #[cfg(feature = "http-stub")]
+    pub fn http_stub(&mut self) -> HttpStub {
+        let settings = self.cloned_setting();
+        match settings.as_ref().http_stub {
+            Some(ref server) => server.stub.clone(),
+            None => panic!("this scene doesn't serve an http stub, call .serve_http_stub() before the first call to its .cmd() or .ucmd()"),
+        }
+    }
+
     pub fn ucmd(&mut self) -> UCommand {
         let settings = self.cloned_setting();
-        let mut cmd = self.cmd(&settings.as_ref().debug_bin_path);
+        let mut cmd = self.runner_cmd(&settings, true);
         cmd.args(&settings.as_ref().subcmd_args);
         cmd
     }
 
+    /// like ucmd(), but the command is run from the provided subdirectory
+    /// of the temporary directory, which must already exist
+    pub fn ucmd_in<P: AsRef<Path>>(&mut self, subdir: P) -> UCommand {
+        let mut cmd = self.ucmd();
+        cmd.current_dir_rel(subdir);
+        cmd
+    }
+
     pub fn cmd<S: AsRef<OsStr>>(&mut self, bin: S) -> UCommand {
         let setting = self.cloned_setting();
         let curdir : Option<&OsStr>  = None;
@@ -155,17 +309,52 @@ impl Scene {
     // because the need to keep the environment is exceedingly rare.
     pub fn ucmd_keepenv(&mut self) -> UCommand {
         let settings = self.cloned_setting();
-        let mut cmd = self.cmd_keepenv(&settings.as_ref().debug_bin_path);
+        let mut cmd = self.runner_cmd(&settings, false);
         cmd.args(&settings.as_ref().subcmd_args);
         cmd
     }
 
+    // a command invoking the binary, under the runner if there is one
+    fn runner_cmd(&mut self, settings: &Arc<SceneSettings>, env_clear: bool) -> UCommand {
+        let bin = settings.as_ref().debug_bin_path.as_os_str();
+        match settings.as_ref().runner.split_first() {
+            Some((runner, runner_args)) => {
+                let mut cmd = if env_clear { self.cmd(runner) } else { self.cmd_keepenv(runner) };
+                cmd.args(runner_args).arg(bin);
+                cmd
+            },
+            None => if env_clear { self.cmd(bin) } else { self.cmd_keepenv(bin) },
+        }
+    }
+
     pub fn cmd_keepenv<S: AsRef<OsStr>>(&mut self, bin: S) -> UCommand {
         let setting = self.cloned_setting();
         let curdir : Option<&OsStr>  = None;
         UCommand::new(bin, setting, false, curdir)
     }
 
+    /// runs the provided commands concurrently and returns their results in order,
+    /// as Batch::new(commands).run()
+    pub fn run_all(&self, commands: Vec<UCommand>) -> BatchResult {
+        Batch::new(commands).run()
+    }
+
+    /// starts the command in the background as a long-running service, and blocks until it's ready
+    /// (for up to 10 seconds, see Service::start(...) to wait longer).
+    /// the service is stopped when the returned guard is dropped.
+    pub fn spawn_service(&self, ucmd: UCommand, ready: Readiness) -> Service {
+        Service::start(ucmd, ready, Duration::from_secs(DEFAULT_SERVICE_READY_TIMEOUT_SECS))
+    }
+
+    /// returns a TCP port on the loopback interface which is currently free, for a service to listen on.
+    /// a port is reserved until the test process exits, through a lock file shared with other
+    /// test processes, so that tests running in parallel (whether in threads or processes)
+    /// don't receive the same port before either binds it. Other programs may still bind it,
+    /// so a service failing to listen is best retried on another port.
+    pub fn free_port(&self) -> u16 {
+        free_port()
+    }
+
     pub fn working_dir(&mut self) -> AtPath {
         let setting = self.cloned_setting();
         AtPath::from_scene_settings(setting)
@@ -187,7 +376,7 @@ impl Scene {
                                     recursive_copy(&fixtures_path, result.as_ref().tmpd.path()).expect("tried to recursively copy fixtures to tmp dir but failed");
                                 },
                                 Err(_) => {
-                                    panic!("error copying to fixtures directory {}. Are you sure it exists?", fixtures_path.to_str().expect("tried to get provided fixtures path as a string but failed"));
+                                    panic!("error copying to fixtures directory {}. Are you sure it exists?", os_display(fixtures_path));
                                 }
                             }
                         }
@@ -202,7 +391,7 @@ impl Scene {
     }
 
     fn generate_setting(&self, builder: &SceneBuilder) -> SceneSettings {
-        SceneSettings {
+        let mut settings = SceneSettings {
             debug_bin_path: {
                 // Instead of hardcoding the path relative to the current
                 // directory, use Cargo's OUT_DIR to find path to executable.
@@ -214,7 +403,7 @@ impl Scene {
                 target_dir.push(
                     builder.debug_bin_subpath.as_ref().unwrap().clone()
                 );
-                PathBuf::from(AtPath::from_path_owned(target_dir).root_dir_resolved().unwrap())
+                AtPath::from_path_owned(target_dir).root_dir_resolved_path().unwrap()
             },
             repo_fixtures_path: {
                 let mut repo_fixtures_subpath = {
@@ -230,7 +419,7 @@ impl Scene {
                 if let Some(ref fixtroot_fixture_subpath) = builder.fixtroot_fixture_subpath {
                     repo_fixtures_subpath.push(fixtroot_fixture_subpath);
                 };
-                AtPath::from_path_owned(repo_fixtures_subpath).root_dir_resolved().map(|x| PathBuf::from(x))
+                AtPath::from_path_owned(repo_fixtures_subpath).root_dir_resolved_path()
             },
             subcmd_args: {
                 let mut result = if let Some(ref subcmd_args) = builder.subcmd_args {
@@ -243,8 +432,61 @@ impl Scene {
                 };
                 result
             },
-            tmpd: TempDir::new("second_law").expect("tried to create a temporary directory but failed")
+            tmpd: TempDir::new("second_law").expect("tried to create a temporary directory but failed"),
+            default_timeout: builder.default_timeout,
+            inherited_env: builder.inherited_env.clone(),
+            env_changes: builder.env_changes.clone(),
+            runner: builder.runner.clone().unwrap_or_else(runner_from_env),
+            forward_instrumentation_env: builder.forward_instrumentation_env,
+            bin_dir: None,
+            linked_bin_path: None,
+            #[cfg(feature = "http-stub")]
+            http_stub: None
+        };
+        if let Some(ref name) = builder.multicall_argv0 {
+            let bin_dir = TempDir::new("second_law-bin").expect("tried to create a temporary directory but failed");
+            // the name is used as is, other than the platform's executable extension, if any
+            let mut file_name = name.clone();
+            if !env::consts::EXE_EXTENSION.is_empty() {
+                file_name.push(".");
+                file_name.push(env::consts::EXE_EXTENSION);
+            }
+            let link = bin_dir.path().join(file_name);
+            link_binary(&settings.debug_bin_path, &link);
+            log_info("multicall link", format!("{} -> {}", os_display(&link), os_display(&settings.debug_bin_path)));
+            settings.linked_bin_path = Some(settings.debug_bin_path.clone());
+            settings.debug_bin_path = link;
+            settings.bin_dir = Some(bin_dir);
+        }
+        #[cfg(feature = "http-stub")]
+        {
+            if builder.http_stub {
+                settings.http_stub = Some(HttpStubServer::start(settings.tmpd.path()));
+            }
         }
+        settings
     }
 }
 
+#[cfg(unix)]
+fn link_binary(bin: &Path, link: &Path) {
+    use std::os::unix::fs::symlink;
+    symlink(bin, link).expect("tried to link the binary for multicall dispatch but failed");
+}
+
+// a hardlink, as symlinks require elevated privileges on Windows,
+// or a copy if the binary is on another volume
+#[cfg(not(unix))]
+fn link_binary(bin: &Path, link: &Path) {
+    fs::hard_link(bin, link).or_else(|_| fs::copy(bin, link).map(|_| ()))
+       .expect("tried to link the binary for multicall dispatch but failed");
+}
+
+// the runner set by SECOND_LAW_RUNNER, or else cargo's runner for the target the tests are built for
+fn runner_from_env() -> Vec<OsString> {
+    let cargo_var = format!("CARGO_TARGET_{}_RUNNER",
+                            env!("SECOND_LAW_TARGET").to_uppercase().replace("-", "_").replace(".", "_"));
+    let runner = env::var(RUNNER_ENV_VAR).or_else(|_| env::var(cargo_var)).unwrap_or_default();
+    runner.split_whitespace().map(OsString::from).collect()
+}
+
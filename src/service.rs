@@ -0,0 +1,220 @@
+extern crate regex;
+#[cfg(unix)]
+extern crate libc;
+
+use std::env;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use self::regex::bytes::Regex;
+
+use super::capture::Stream;
+use super::cmdresult::CmdResult;
+use super::common::log_info;
+use super::process::has_exited;
+#[cfg(unix)]
+use super::process::send_signal;
+use super::running::Running;
+use super::ucommand::UCommand;
+
+static DEFAULT_STOP_GRACE_SECS: u64 = 5;
+static READY_POLL_INTERVAL_MS: u64 = 10;
+static CONNECT_TIMEOUT_MS: u64 = 100;
+static ALREADY_STOPPED: &str = "this service has already been stopped";
+
+static PORT_LOCKS_DIR: &str = "second_law-ports";
+
+// ports handed out by free_port(), each with its lock file, which is held until this process exits
+static RESERVED_PORTS: Mutex<Vec<(u16, File)>> = Mutex::new(Vec::new());
+
+/// What shows that a service has started and is ready to be used
+#[derive(Clone, Debug, PartialEq)]
+pub enum Readiness {
+    /// a match for the regex appears in the service's output on the stream
+    Output(Stream, String),
+    /// a file appears at the path relative to the scene's temporary directory
+    File(PathBuf),
+    /// a TCP listener accepts connections at the address
+    Tcp(SocketAddr),
+    /// a unix domain socket at the path relative to the scene's temporary directory
+    /// accepts connections. Only supported on unix platforms
+    UnixSocket(PathBuf),
+}
+
+/// returns a TCP port on the loopback interface which is currently free, and which isn't
+/// returned again until this process exits, by this process or another using second_law
+/// (e.g. when a test runner runs each test in its own process).
+/// The port is reserved through a lock file in the system's temporary directory,
+/// which can't keep unrelated programs from binding it in the meantime.
+pub fn free_port() -> u16 {
+    let mut reserved = RESERVED_PORTS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    loop {
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|listener| listener.local_addr())
+            .expect("tried to find a free port but failed")
+            .port();
+        if reserved.iter().any(|&(reserved_port, _)| reserved_port == port) {
+            continue;
+        }
+        match lock_port(port) {
+            Ok(Some(lock)) => {
+                reserved.push((port, lock));
+                return port;
+            },
+            // reserved by another process
+            Ok(None) => continue,
+            Err(e) => {
+                log_info("free port", format!("tried to reserve port {} but failed, so other processes may receive it: {}", port, e));
+                return port;
+            },
+        }
+    }
+}
+
+// locks the file of the port, returning None if another process holds it
+fn lock_port(port: u16) -> io::Result<Option<File>> {
+    let dir = env::temp_dir().join(PORT_LOCKS_DIR);
+    fs::create_dir_all(&dir)?;
+    let file = OpenOptions::new().write(true).create(true).truncate(false).open(dir.join(port.to_string()))?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// A Service is a long-running command, such as a daemon, started in the background
+/// for other commands of the scene to interact with.
+/// When stopped (or dropped), it is sent SIGTERM, then killed along with anything it spawned
+/// if it hasn't exited within a grace period. If a test fails while it's running,
+/// its output is displayed.
+pub struct Service {
+    running: Running,
+    stop_grace: Duration,
+    stopped: bool,
+}
+
+impl Service {
+    /// spawns the command, then blocks until it's ready, failing if it
+    /// exits or doesn't become ready within the provided timeout
+    pub fn start(mut ucmd: UCommand, ready: Readiness, ready_timeout: Duration) -> Service {
+        log_info("start service", format!("{} (ready on {:?})", ucmd.comm_string(), ready));
+        let tmpdir = PathBuf::from(ucmd.settings().as_ref().tmpd.path());
+        let mut service = Service {
+            running: ucmd.start_service(),
+            stop_grace: Duration::from_secs(DEFAULT_STOP_GRACE_SECS),
+            stopped: false,
+        };
+        let pattern = match ready {
+            Readiness::Output(_, ref pattern) => Some(Regex::new(pattern).unwrap_or_else(
+                |e| panic!("invalid readiness pattern {:?}: {}", pattern, e))),
+            _ => None,
+        };
+        let deadline = Instant::now() + ready_timeout;
+        loop {
+            let is_ready = match ready {
+                Readiness::Output(stream, _) => {
+                    let output = match stream {
+                        Stream::Stdout => service.running.stdout.contents(),
+                        Stream::Stderr => service.running.stderr.contents(),
+                    };
+                    pattern.as_ref().unwrap().is_match(&output)
+                },
+                Readiness::File(ref path) => tmpdir.join(path).exists(),
+                Readiness::Tcp(addr) => TcpStream::connect_timeout(&addr, Duration::from_millis(CONNECT_TIMEOUT_MS)).is_ok(),
+                Readiness::UnixSocket(ref path) => unix_socket_accepts(tmpdir.join(path)),
+            };
+            if is_ready {
+                return service;
+            }
+            // the service's output is displayed as it's dropped
+            if has_exited(&service.running.child) {
+                let result = service.stop();
+                panic!("expected {} to become ready on {:?}, but it exited with {}",
+                       service.running.comm_string, ready, result.status);
+            }
+            if Instant::now() >= deadline {
+                panic!("expected {} to become ready on {:?} within {:?}, but it didn't",
+                       service.running.comm_string, ready, ready_timeout);
+            }
+            sleep(Duration::from_millis(READY_POLL_INTERVAL_MS));
+        }
+    }
+
+    /// sets how long the service is given to exit after SIGTERM before being killed
+    pub fn stop_grace(&mut self, grace: Duration) -> Box<&mut Service> {
+        self.stop_grace = grace;
+        Box::new(self)
+    }
+
+    /// returns the process id of the service
+    pub fn pid(&self) -> u32 {
+        self.running.child.id()
+    }
+
+    /// returns everything the service has written to stdout so far
+    pub fn stdout(&self) -> Vec<u8> {
+        self.running.stdout.contents()
+    }
+
+    /// returns everything the service has written to stderr so far
+    pub fn stderr(&self) -> Vec<u8> {
+        self.running.stderr.contents()
+    }
+
+    /// describes everything the service has written to stdout and stderr so far
+    pub fn logs(&self) -> String {
+        format!("service stdout:\n{}\nservice stderr:\n{}",
+                String::from_utf8_lossy(&self.stdout()), String::from_utf8_lossy(&self.stderr()))
+    }
+
+    /// sends the service SIGTERM (on platforms without signals, kills it), waits for it to exit,
+    /// killing it and anything it spawned if it hasn't within the grace period, and returns its result.
+    /// The result is marked as timed out if it had to be killed.
+    pub fn stop(&mut self) -> CmdResult {
+        if self.stopped {
+            panic!("{}", ALREADY_STOPPED);
+        }
+        self.stopped = true;
+        log_info("stop service", &self.running.comm_string);
+        self.terminate();
+        self.running.deadline = Some(Instant::now() + self.stop_grace);
+        self.running.finish()
+    }
+
+    #[cfg(unix)]
+    fn terminate(&mut self) {
+        send_signal(self.pid(), libc::SIGTERM, &self.running.reaped);
+    }
+
+    #[cfg(not(unix))]
+    fn terminate(&mut self) {
+        self.running.kill();
+    }
+}
+
+#[cfg(unix)]
+fn unix_socket_accepts(path: PathBuf) -> bool {
+    ::std::os::unix::net::UnixStream::connect(path).is_ok()
+}
+
+#[cfg(not(unix))]
+fn unix_socket_accepts(_path: PathBuf) -> bool {
+    panic!("unix domain sockets are only supported on unix platforms");
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            log_info("service", format!("{}\n{}", self.running.comm_string, self.logs()));
+        }
+        if !self.stopped {
+            self.stop();
+        }
+    }
+}
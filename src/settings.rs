@@ -2,11 +2,32 @@ extern crate tempdir;
 
 use std::ffi::OsString;
 use std::path::PathBuf;
+use std::time::Duration;
 use self::tempdir::TempDir;
 
+#[cfg(feature = "http-stub")]
+use super::http_stub::HttpStubServer;
+
 pub struct SceneSettings {
     pub debug_bin_path: PathBuf,
     pub repo_fixtures_path: Option<PathBuf>,
     pub subcmd_args: Vec<OsString>,
     pub tmpd: TempDir,
+    pub default_timeout: Option<Duration>,
+    pub inherited_env: Vec<OsString>,
+    // variables set (Some) or removed (None) for every command, in order
+    pub env_changes: Vec<(OsString, Option<OsString>)>,
+    // the command, if any, every ucmd() is run under
+    pub runner: Vec<OsString>,
+    // whether coverage and sanitizer variables are kept when a command's environment is cleared
+    pub forward_instrumentation_env: bool,
+    // holds the link to the binary named for multicall dispatch on argv[0], if any.
+    // kept apart from tmpd so that it doesn't appear among the command's files
+    pub bin_dir: Option<TempDir>,
+    // the binary the link in bin_dir leads to, which repro scripts link to again,
+    // as bin_dir is deleted along with the scene
+    pub linked_bin_path: Option<PathBuf>,
+    // the server {http_stub} is replaced with the url of, if the scene serves one
+    #[cfg(feature = "http-stub")]
+    pub http_stub: Option<HttpStubServer>,
 }
@@ -1,32 +1,83 @@
+use std::collections::BTreeMap;
 use std::env;
-use std::ffi::OsStr;
-use std::io::Write;
-use std::process::{Command, Stdio, Child};
-use std::str::from_utf8;
+use std::fs::{self, File};
+use std::ffi::{OsStr, OsString};
+use std::process::{Command, Stdio, Child, ChildStdout};
 use std::sync::Arc;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use super::capture::{feed, Capture};
 use super::cmdresult::CmdResult;
-use super::fixtures::read_scenario_fixture;
-use super::common::log_info;
+use super::fixtures::read_scenario_fixture_bytes;
+use super::common::{log_info, os_display, os_string_from_bytes};
+use super::instrumentation::instrumentation_env;
+use super::interactive::InteractiveSession;
+use super::process::{close_stdin_on_exec, own_process_group, set_resource_limits, Leftovers, ResourceLimit};
+#[cfg(unix)]
+use super::process::pass_fds;
+use super::pty::{Pty, DEFAULT_PTY_COLS, DEFAULT_PTY_ROWS};
+use super::repro::{shell_word, Repro};
+use super::running::Running;
 use super::settings::SceneSettings;
 
 static ALREADY_RUN: &'static str = "you have already run this UCommand, if you want to run \
                                     another command in the same test, use scene.ucmd()";
 static MULTIPLE_STDIN_MEANINGLESS: &'static str = "Ucommand is designed around a typical use case of: provide args and input stream -> spawn process -> block until completion -> return output streams. For verifying that a particular section of the input stream is what causes a particular behavior, use the Command type directly.";
 
+/// Where an extra file descriptor passed to a command with UCommand::pass_fd(...) leads
+#[derive(Clone, Debug, PartialEq)]
+pub enum FdSource {
+    /// a pipe, everything written to which is captured into the command result
+    Capture,
+    /// the file at the provided path relative to the scene's temporary directory, opened for reading
+    ReadFile(PathBuf),
+    /// the file at the provided path relative to the scene's temporary directory,
+    /// created (or truncated) and opened for writing
+    WriteFile(PathBuf),
+}
+
+// where the stdin of a command is read from
+#[derive(Clone, Debug, PartialEq)]
+enum StdinSource {
+    // a pipe, into which any input provided by pipe_in(...) is fed
+    Pipe,
+    Null,
+    Closed,
+    // an absolute path to a file
+    File(PathBuf),
+}
+
 /// A UCommand is a wrapper around an individual Command that provides several additional features
 /// 1. it has convenience functions that are more ergonomic to use for piping in stdin, spawning the command
-///       and asserting on the results.
+///    and asserting on the results.
 /// 2. it tracks arguments provided so that in test cases which may provide variations of an arg in loops
-///     the test failure can display the exact call which preceded an assertion failure.
+///    the test failure can display the exact call which preceded an assertion failure.
+///    A shell script reproducing the call, with a copy of the scene's temporary directory,
+///    is also written out and its path printed.
 /// 3. it provides convenience construction arguments to set the Command working directory and/or clear its environment.
 pub struct UCommand {
     pub raw: Command,
     comm_string: String,
     settings: Arc<SceneSettings>,
     has_run: bool,
-    stdin: Option<Vec<u8>>
+    stdin: Option<Vec<u8>>,
+    stdin_source: StdinSource,
+    timeout: Option<Duration>,
+    pty: Option<(u16, u16)>,
+    stdout_read_limit: Option<usize>,
+    capture_combined: bool,
+    extra_fds: Vec<(i32, FdSource)>,
+    fd_captures: Vec<(i32, Capture)>,
+    stdout_file: Option<PathBuf>,
+    stderr_file: Option<PathBuf>,
+    resource_limits: Vec<(ResourceLimit, u64)>,
+    leftovers: Leftovers,
+    env_cleared: bool,
+    // variables set (Some) or removed (None) since the environment was cleared or inherited, in order
+    env_changes: Vec<(OsString, Option<OsString>)>,
+    curdir: PathBuf,
+    repro: Option<Arc<Repro>>
 }
 
 impl UCommand {
@@ -42,66 +93,83 @@ impl UCommand {
             PathBuf::from(settings.as_ref().tmpd.path())
         };
 //        let  = String::from(&(*tmpd.as_ref().path().to_str().unwrap()));
-        UCommand {
-            settings: settings,
+        let timeout = settings.as_ref().default_timeout;
+        let mut ucmd = UCommand {
+            settings: settings.clone(),
             has_run: false,
             raw: {
                 let mut cmd = Command::new(invoked.as_ref());
                 cmd.current_dir(&curdir_used);
-                if env_clear {
-                    if cfg!(windows) {
-                        // %SYSTEMROOT% is required on Windows to initialize crypto provider
-                        // ... and crypto provider is required for std::rand
-                        // From procmon: RegQueryValue HKLM\SOFTWARE\Microsoft\Cryptography\Defaults\Provider\Microsoft Strong Cryptographic Provider\Image Path
-                        // SUCCESS  Type: REG_SZ, Length: 66, Data: %SystemRoot%\system32\rsaenh.dll"
-                        for (key, _) in env::vars_os() {
-                            if key.as_os_str() != "SYSTEMROOT" {
-                                cmd.env_remove(key);
-                            }
-                        }
-                    } else {
-                        cmd.env_clear();
-                    }
-                }
                 cmd
             },
-            comm_string: String::from(invoked.as_ref().to_str().unwrap()),
-            stdin: None
+            comm_string: os_display(invoked.as_ref()),
+            stdin: None,
+            stdin_source: StdinSource::Pipe,
+            timeout,
+            pty: None,
+            stdout_read_limit: None,
+            capture_combined: false,
+            extra_fds: Vec::new(),
+            fd_captures: Vec::new(),
+            stdout_file: None,
+            stderr_file: None,
+            resource_limits: Vec::new(),
+            leftovers: Leftovers::Ignore,
+            env_cleared: false,
+            env_changes: Vec::new(),
+            curdir: curdir_used,
+            repro: None
+        };
+        if env_clear {
+            ucmd.env_clear();
+        } else if settings.as_ref().forward_instrumentation_env {
+            // the inherited variables are kept, but profiles still need to be unique
+            ucmd.envs(instrumentation_env());
+        }
+        ucmd.inherit_env(&settings.as_ref().inherited_env);
+        for (key, val) in settings.as_ref().env_changes.iter() {
+            match *val {
+                Some(ref val) => ucmd.env(key, val),
+                None => ucmd.env_remove(key),
+            };
         }
+        ucmd
     }
 
     pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> Box<&mut UCommand> {
         if self.has_run {
             panic!(ALREADY_RUN);
         }
+        let arg = self.expand_placeholders(arg.as_ref());
         self.comm_string.push_str(" ");
-        self.comm_string.push_str(arg.as_ref().to_str().unwrap());
-        self.raw.arg(arg.as_ref());
+        self.comm_string.push_str(&os_display(&arg));
+        self.raw.arg(arg);
         Box::new(self)
     }
 
     /// like arg(...), but uses the contents of the file at the provided relative path as the argument
     pub fn arg_fixture<S: AsRef<OsStr>>(&mut self, file_rel_path: S) -> Box<&mut UCommand> {
-        let contents = read_scenario_fixture(&self.settings, file_rel_path);
-        self.arg(contents)
+        let contents = read_scenario_fixture_bytes(&self.settings, file_rel_path);
+        self.arg(os_string_from_bytes(contents))
     }
 
     pub fn args<S: AsRef<OsStr>>(&mut self, args: &[S]) -> Box<&mut UCommand> {
         if self.has_run {
             panic!(MULTIPLE_STDIN_MEANINGLESS);
         }
-        for s in args {
+        let args: Vec<OsString> = args.iter().map(|s| self.expand_placeholders(s.as_ref())).collect();
+        for s in args.iter() {
             self.comm_string.push_str(" ");
-            self.comm_string.push_str(s.as_ref().to_str().unwrap());
+            self.comm_string.push_str(&os_display(s));
         }
 
-        self.raw.args(args.as_ref());
+        self.raw.args(args);
         Box::new(self)
     }
 
     /// provides stdinput to feed in to the command when spawned
     pub fn pipe_in<T: Into<Vec<u8>>>(&mut self, input: T) -> Box<&mut UCommand> {
-        if self.stdin.is_some() {
+        if self.stdin.is_some() || self.stdin_source != StdinSource::Pipe {
             panic!(MULTIPLE_STDIN_MEANINGLESS);
         }
         self.stdin = Some(input.into());
@@ -110,41 +178,698 @@ impl UCommand {
 
     /// like pipe_in(...), but uses the contents of the file at the provided relative path as the piped in data
     pub fn pipe_in_fixture<S: AsRef<OsStr>>(&mut self, file_rel_path: S) -> Box<&mut UCommand> {
-        let contents = read_scenario_fixture(&self.settings, file_rel_path);
+        let contents = read_scenario_fixture_bytes(&self.settings, file_rel_path);
         self.pipe_in(contents)
     }
 
+    fn set_stdin_source(&mut self, source: StdinSource) -> &mut UCommand {
+        if self.has_run {
+            panic!("{}", ALREADY_RUN);
+        }
+        if self.stdin.is_some() || self.stdin_source != StdinSource::Pipe {
+            panic!("{}", MULTIPLE_STDIN_MEANINGLESS);
+        }
+        self.stdin_source = source;
+        self
+    }
+
+    /// makes the command's stdin the null device (/dev/null, or NUL on Windows),
+    /// which is immediately at end-of-file, rather than a pipe
+    pub fn stdin_null(&mut self) -> Box<&mut UCommand> {
+        Box::new(self.set_stdin_source(StdinSource::Null))
+    }
+
+    /// runs the command with no stdin at all: file descriptor 0 is closed,
+    /// so reading from it fails with EBADF. Only supported on unix platforms.
+    pub fn stdin_closed(&mut self) -> Box<&mut UCommand> {
+        Box::new(self.set_stdin_source(StdinSource::Closed))
+    }
+
+    /// redirects the file at the provided path, relative to the scene's temporary directory,
+    /// directly to the command's stdin (as `< file` in a shell), so unlike pipe_in_fixture(...)
+    /// stdin is a regular file that the command can seek or map.
+    pub fn stdin_file<P: AsRef<Path>>(&mut self, file_rel_path: P) -> Box<&mut UCommand> {
+        let path = self.settings.as_ref().tmpd.path().join(file_rel_path.as_ref());
+        if !path.is_file() {
+            panic!("tried to redirect {:?} to stdin, but it is not an existing file within the scene's temporary directory",
+                   file_rel_path.as_ref());
+        }
+        Box::new(self.set_stdin_source(StdinSource::File(path)))
+    }
+
+    // the stdin of the command when it's not read from the previous stage of a pipeline
+    fn stdin_stdio(&mut self) -> Stdio {
+        match self.stdin_source {
+            StdinSource::Pipe => Stdio::piped(),
+            StdinSource::Null => Stdio::null(),
+            StdinSource::Closed => {
+                close_stdin_on_exec(&mut self.raw);
+                Stdio::null()
+            },
+            StdinSource::File(ref path) => Stdio::from(
+                File::open(path).unwrap_or_else(|e| panic!("failed to open {:?} for stdin: {}", path, e))),
+        }
+    }
+
     pub fn env<K, V>(&mut self, key: K, val: V) -> Box<&mut UCommand> where K: AsRef<OsStr>, V: AsRef<OsStr> {
         if self.has_run {
             panic!(ALREADY_RUN);
         }
-        self.raw.env(key, val);
+        let val = self.expand_placeholders(val.as_ref());
+        self.raw.env(key.as_ref(), &val);
+        self.env_changes.push((OsString::from(key.as_ref()), Some(val)));
         Box::new(self)
     }
 
-    /// Spawns the command, feeds the stdin if any, and returns the
-    /// child process immediately.
-    pub fn run_no_wait(&mut self) -> Child {
+    /// like env(...), for each of the provided key-value pairs
+    pub fn envs<I, K, V>(&mut self, vars: I) -> Box<&mut UCommand>
+        where I: IntoIterator<Item = (K, V)>, K: AsRef<OsStr>, V: AsRef<OsStr>
+    {
+        for (key, val) in vars {
+            self.env(key, val);
+        }
+        Box::new(self)
+    }
+
+    /// removes the variable from the command's environment
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> Box<&mut UCommand> {
+        if self.has_run {
+            panic!("{}", ALREADY_RUN);
+        }
+        self.raw.env_remove(key.as_ref());
+        self.env_changes.push((OsString::from(key.as_ref()), None));
+        Box::new(self)
+    }
+
+    /// clears the command's environment, including any variables already set on it,
+    /// so that it doesn't depend on the environment the tests are run in.
+    /// commands created by scene.ucmd() and scene.cmd() start with a cleared environment.
+    /// Coverage and sanitizer variables are kept, unless disabled by Scene::forward_instrumentation_env(false).
+    pub fn env_clear(&mut self) -> Box<&mut UCommand> {
+        if self.has_run {
+            panic!("{}", ALREADY_RUN);
+        }
+        self.env_cleared = true;
+        self.env_changes.clear();
+        self.raw.env_clear();
+        if cfg!(windows) {
+            // %SYSTEMROOT% is required on Windows to initialize crypto provider
+            // ... and crypto provider is required for std::rand
+            // From procmon: RegQueryValue HKLM\SOFTWARE\Microsoft\Cryptography\Defaults\Provider\Microsoft Strong Cryptographic Provider\Image Path
+            // SUCCESS  Type: REG_SZ, Length: 66, Data: %SystemRoot%\system32\rsaenh.dll"
+            if let Some(systemroot) = env::var_os("SYSTEMROOT") {
+                self.raw.env("SYSTEMROOT", systemroot);
+            }
+        }
+        if self.settings.as_ref().forward_instrumentation_env {
+            self.envs(instrumentation_env());
+        }
+        Box::new(self)
+    }
+
+    /// copies the provided variables, where set, from the environment the tests are run in
+    /// to the command's environment. Useful for allowing through variables such as PATH
+    /// or LANG when the environment is otherwise cleared.
+    pub fn inherit_env<S: AsRef<OsStr>>(&mut self, keys: &[S]) -> Box<&mut UCommand> {
+        for key in keys {
+            if let Some(val) = env::var_os(key.as_ref()) {
+                self.env(key, val);
+            }
+        }
+        Box::new(self)
+    }
+
+    /// returns the environment the command will be run with, sorted by key
+    pub fn effective_env(&self) -> BTreeMap<OsString, OsString> {
+        let mut effective = BTreeMap::new();
+        if !self.env_cleared {
+            effective.extend(env::vars_os());
+        } else if cfg!(windows) {
+            effective.extend(env::var_os("SYSTEMROOT").map(|val| (OsString::from("SYSTEMROOT"), val)));
+        }
+        for (key, val) in self.env_changes.iter() {
+            match *val {
+                Some(ref val) => effective.insert(key.clone(), val.clone()),
+                None => effective.remove(key),
+            };
+        }
+        effective
+    }
+
+    // replaces "{http_stub}" with the url of the scene's http stub, if it serves one
+    #[cfg(feature = "http-stub")]
+    fn expand_placeholders(&self, value: &OsStr) -> OsString {
+        match self.settings.as_ref().http_stub {
+            Some(ref server) => server.stub.expand(value),
+            None => OsString::from(value),
+        }
+    }
+
+    #[cfg(not(feature = "http-stub"))]
+    fn expand_placeholders(&self, value: &OsStr) -> OsString {
+        OsString::from(value)
+    }
+
+    // describes the environment for logging. An inherited environment is described
+    // by its changes alone, rather than dumping every variable of the test runner.
+    fn env_string(&self) -> String {
+        let vars: Vec<String> = if self.env_cleared {
+            self.effective_env().iter()
+                .map(|(key, val)| format!("{}={:?}", os_display(key), val))
+                .collect()
+        } else {
+            let mut changes = vec![String::from("(inherited)")];
+            for (key, val) in self.env_changes.iter() {
+                changes.push(match *val {
+                    Some(ref val) => format!("{}={:?}", os_display(key), val),
+                    None => format!("-{}", os_display(key)),
+                });
+            }
+            changes
+        };
+        if vars.is_empty() {
+            String::from("(empty)")
+        } else {
+            vars.join(" ")
+        }
+    }
+
+    /// runs the command from the provided subdirectory of the scene's temporary directory,
+    /// rather than from the temporary directory itself. The subdirectory must already exist.
+    pub fn current_dir_rel<P: AsRef<Path>>(&mut self, subdir: P) -> Box<&mut UCommand> {
+        if self.has_run {
+            panic!("{}", ALREADY_RUN);
+        }
+        let tmpdir = self.settings.as_ref().tmpd.path();
+        let curdir = tmpdir.join(subdir.as_ref());
+        let within_tmpdir = match (curdir.canonicalize(), tmpdir.canonicalize()) {
+            (Ok(resolved), Ok(tmpdir_resolved)) => resolved.is_dir() && resolved.starts_with(tmpdir_resolved),
+            _ => false,
+        };
+        if !within_tmpdir {
+            panic!("tried to run a command in {:?}, but it is not an existing directory within the scene's temporary directory",
+                   subdir.as_ref());
+        }
+        self.raw.current_dir(&curdir);
+        self.curdir = curdir;
+        Box::new(self)
+    }
+
+    // describes the working directory for logging, relative to the scene's temporary directory if within it
+    fn cwd_string(&self) -> String {
+        match self.curdir.strip_prefix(self.settings.as_ref().tmpd.path()) {
+            Ok(rel) if rel.as_os_str().is_empty() => String::from("."),
+            Ok(rel) => os_display(rel),
+            Err(_) => os_display(&self.curdir),
+        }
+    }
+
+    /// kills the command, and every process it has spawned, if it has not
+    /// completed within the provided duration. Overrides the scene's timeout, if any.
+    pub fn timeout(&mut self, timeout: Duration) -> Box<&mut UCommand> {
+        if self.has_run {
+            panic!("{}", ALREADY_RUN);
+        }
+        self.timeout = Some(timeout);
+        Box::new(self)
+    }
+
+    /// runs the command attached to a pseudo-terminal of the default size (80x24),
+    /// so that it behaves as it would when run interactively in a terminal.
+    /// All terminal output, from both stdout and stderr, is captured as stdout,
+    /// with the terminal's usual translation of "\n" to "\r\n".
+    /// Only supported on unix platforms.
+    pub fn pty(&mut self) -> Box<&mut UCommand> {
+        self.pty_size(DEFAULT_PTY_COLS, DEFAULT_PTY_ROWS)
+    }
+
+    /// like pty(), but with a terminal of the provided size
+    pub fn pty_size(&mut self, cols: u16, rows: u16) -> Box<&mut UCommand> {
+        if self.has_run {
+            panic!("{}", ALREADY_RUN);
+        }
+        self.pty = Some((cols, rows));
+        Box::new(self)
+    }
+
+    /// reads only the first n bytes of the command's stdout, then closes it,
+    /// as a consumer such as `head` would. Further writes to stdout by the command
+    /// then fail with EPIPE, or terminate it with SIGPIPE.
+    /// see CmdResult::broken_pipe_quietly() for asserting on how it handles that.
+    pub fn stdout_read_limit(&mut self, n_bytes: usize) -> Box<&mut UCommand> {
+        if self.has_run {
+            panic!("{}", ALREADY_RUN);
+        }
+        self.stdout_read_limit = Some(n_bytes);
+        Box::new(self)
+    }
+
+    /// in addition to capturing stdout and stderr separately, records the chunks of output
+    /// read from each in the order they arrived, so that the ordering of output across
+    /// the two streams can be asserted on with CmdResult::combined_output() and related assertions.
+    /// As the two streams are separate pipes, output written to both at nearly the same
+    /// moment may be recorded in either order.
+    pub fn capture_combined(&mut self) -> Box<&mut UCommand> {
+        if self.has_run {
+            panic!("{}", ALREADY_RUN);
+        }
+        self.capture_combined = true;
+        Box::new(self)
+    }
+
+    /// passes an additional file descriptor to the command, numbered n, leading to the provided source.
+    /// for binaries which take options such as `--status-fd 3`.
+    /// output written to an FdSource::Capture is available from CmdResult::fd_bytes(n).
+    /// Only supported on unix platforms.
+    pub fn pass_fd(&mut self, n: i32, source: FdSource) -> Box<&mut UCommand> {
+        if self.has_run {
+            panic!("{}", ALREADY_RUN);
+        }
+        if n <= 2 {
+            panic!("file descriptors 0 to 2 are stdin, stdout and stderr, pass_fd(...) is for additional descriptors");
+        }
+        let source = match source {
+            FdSource::Capture => FdSource::Capture,
+            FdSource::ReadFile(path) => FdSource::ReadFile(self.scene_file_path(&path)),
+            FdSource::WriteFile(path) => FdSource::WriteFile(self.scene_file_path(&path)),
+        };
+        self.extra_fds.retain(|&(existing, _)| existing != n);
+        self.extra_fds.push((n, source));
+        Box::new(self)
+    }
+
+    /// redirects the command's stdout directly into the file at the provided path,
+    /// relative to the scene's temporary directory, creating or truncating it (as `> file` in a shell).
+    /// stdout is then not captured.
+    pub fn stdout_to_file<P: AsRef<Path>>(&mut self, file_rel_path: P) -> Box<&mut UCommand> {
+        if self.has_run {
+            panic!("{}", ALREADY_RUN);
+        }
+        self.stdout_file = Some(self.scene_file_path(file_rel_path.as_ref()));
+        Box::new(self)
+    }
+
+    /// like stdout_to_file(...), but for stderr
+    pub fn stderr_to_file<P: AsRef<Path>>(&mut self, file_rel_path: P) -> Box<&mut UCommand> {
+        if self.has_run {
+            panic!("{}", ALREADY_RUN);
+        }
+        self.stderr_file = Some(self.scene_file_path(file_rel_path.as_ref()));
+        Box::new(self)
+    }
+
+    // the path of a file within the scene's temporary directory, which needn't exist yet
+    // (but whose directory must), panicking if it would lead outside of it
+    fn scene_file_path(&self, file_rel_path: &Path) -> PathBuf {
+        let tmpdir = self.settings.as_ref().tmpd.path();
+        let path = tmpdir.join(file_rel_path);
+        // an existing file, or link, is resolved as a whole, so that a link can't lead outside
+        let resolved = if fs::symlink_metadata(&path).is_ok() {
+            path.canonicalize().ok()
+        } else {
+            match (path.parent(), path.file_name()) {
+                (Some(dir), Some(name)) => dir.canonicalize().ok().map(|dir| dir.join(name)),
+                _ => None,
+            }
+        };
+        let within_tmpdir = match (resolved, tmpdir.canonicalize()) {
+            (Some(resolved), Ok(tmpdir_resolved)) => resolved != tmpdir_resolved && resolved.starts_with(tmpdir_resolved),
+            _ => false,
+        };
+        if !within_tmpdir {
+            panic!("tried to use {:?} for the command, but it is not a file within an existing directory of the scene's temporary directory",
+                   file_rel_path);
+        }
+        path
+    }
+
+    fn output_stdio(file: &Option<PathBuf>) -> Stdio {
+        match *file {
+            Some(ref path) => Stdio::from(
+                File::create(path).unwrap_or_else(|e| panic!("failed to create {:?} for output: {}", path, e))),
+            None => Stdio::piped(),
+        }
+    }
+
+    // opens the source of every extra file descriptor and arranges for the command to receive
+    // them, starting captures of those which are pipes. returns the parent's handles to the
+    // sources, which must be kept open until the command is spawned.
+    #[cfg(unix)]
+    fn open_extra_fds(&mut self) -> Vec<::std::os::unix::io::OwnedFd> {
+        use std::io::pipe;
+        use std::os::unix::io::{AsRawFd, OwnedFd};
+
+        let mut handles: Vec<OwnedFd> = Vec::new();
+        let mut mappings = Vec::new();
+        for &(n, ref source) in self.extra_fds.iter() {
+            let handle = match *source {
+                FdSource::Capture => {
+                    let (reader, writer) = pipe().expect("failed to create a pipe");
+                    self.fd_captures.push((n, Capture::spawn(reader)));
+                    OwnedFd::from(writer)
+                },
+                FdSource::ReadFile(ref path) => OwnedFd::from(
+                    File::open(path).unwrap_or_else(|e| panic!("failed to open {:?} for fd {}: {}", path, n, e))),
+                FdSource::WriteFile(ref path) => OwnedFd::from(
+                    File::create(path).unwrap_or_else(|e| panic!("failed to create {:?} for fd {}: {}", path, n, e))),
+            };
+            mappings.push((handle.as_raw_fd(), n));
+            handles.push(handle);
+        }
+        pass_fds(&mut self.raw, mappings);
+        handles
+    }
+
+    #[cfg(not(unix))]
+    fn open_extra_fds(&mut self) -> Vec<()> {
+        panic!("passing additional file descriptors is only supported on unix platforms");
+    }
+
+    /// runs the command in its own process group and, once it exits, records any processes
+    /// it spawned which are still running (or are zombies) in that group, for asserting on
+    /// with CmdResult::no_leftover_processes(). Descendants which start their own process group
+    /// or session, as daemons do, aren't found. Only supported on unix platforms.
+    pub fn track_leftover_processes(&mut self) -> Box<&mut UCommand> {
+        Box::new(self.set_leftovers(Leftovers::Track))
+    }
+
+    /// like track_leftover_processes(), but also kills any leftover processes once they are recorded,
+    /// so that they can't hold on to resources or interfere with later commands
+    pub fn kill_leftover_processes(&mut self) -> Box<&mut UCommand> {
+        Box::new(self.set_leftovers(Leftovers::Kill))
+    }
+
+    fn set_leftovers(&mut self, leftovers: Leftovers) -> &mut UCommand {
+        if self.has_run {
+            panic!("{}", ALREADY_RUN);
+        }
+        if cfg!(not(unix)) {
+            panic!("tracking leftover processes is only supported on unix platforms");
+        }
+        self.leftovers = leftovers;
+        self
+    }
+
+    fn limit(&mut self, resource: ResourceLimit, value: u64) -> &mut UCommand {
+        if self.has_run {
+            panic!("{}", ALREADY_RUN);
+        }
+        self.resource_limits.retain(|&(existing, _)| existing != resource);
+        self.resource_limits.push((resource, value));
+        self
+    }
+
+    /// limits the virtual memory (address space) of the command, in bytes, so that
+    /// allocations beyond it fail. Applied through setrlimit, so only supported on unix platforms.
+    pub fn limit_memory(&mut self, bytes: u64) -> Box<&mut UCommand> {
+        Box::new(self.limit(ResourceLimit::Memory, bytes))
+    }
+
+    /// limits the number of file descriptors the command can have open,
+    /// so that opening more fails with EMFILE. Note that stdin, stdout and stderr count towards it.
+    /// Only supported on unix platforms.
+    pub fn limit_open_files(&mut self, count: u64) -> Box<&mut UCommand> {
+        Box::new(self.limit(ResourceLimit::OpenFiles, count))
+    }
+
+    /// limits the cpu time of the command (rounded up to whole seconds),
+    /// after which it is terminated with SIGXCPU. Only supported on unix platforms.
+    pub fn limit_cpu_time(&mut self, cpu_time: Duration) -> Box<&mut UCommand> {
+        let secs = cpu_time.as_secs() + if cpu_time.subsec_nanos() > 0 { 1 } else { 0 };
+        Box::new(self.limit(ResourceLimit::CpuTime, secs))
+    }
+
+    /// limits the size of files the command can write, in bytes. Writing past it
+    /// raises SIGXFSZ, which terminates the command unless it ignores that signal,
+    /// in which case the write fails with EFBIG. Only supported on unix platforms.
+    pub fn limit_file_size(&mut self, bytes: u64) -> Box<&mut UCommand> {
+        Box::new(self.limit(ResourceLimit::FileSize, bytes))
+    }
+
+    /// limits the number of processes which can exist for the user running the command
+    /// (not just its descendants), so that forking beyond it fails with EAGAIN.
+    /// Not enforced for root. Only supported on unix platforms.
+    pub fn limit_processes(&mut self, count: u64) -> Box<&mut UCommand> {
+        Box::new(self.limit(ResourceLimit::Processes, count))
+    }
+
+    // describes how to run the command from a shell, for reproducing it after a test fails
+    fn repro(&self) -> Repro {
+        let tmpdir = self.settings.as_ref().tmpd.path();
+        Repro::new(self.comm_string.clone(), PathBuf::from(tmpdir), self.repro_steps(false), self.stdin.clone())
+    }
+
+    // the shell commands run by repro(), leaving stdin as is if fed by the previous command of a pipeline
+    pub(crate) fn repro_steps(&self, fed_by_pipe: bool) -> String {
+        let tmpdir = self.settings.as_ref().tmpd.path();
+        let mut body = String::new();
+        if self.pty.is_some() {
+            body.push_str("# originally run attached to a pseudo-terminal\n");
+        }
+        if let Some(timeout) = self.timeout {
+            body.push_str(&format!("# originally run with a timeout of {:?}\n", timeout));
+        }
+        for &(resource, value) in self.resource_limits.iter() {
+            body.push_str(&format!("# originally run with a {:?} limit of {}\n", resource, value));
+        }
+        let settings = self.settings.as_ref();
+        // the link a multicall binary is invoked through is deleted along with the scene,
+        // so the script makes its own, in the same way and with the same name
+        let relinked = match settings.linked_bin_path {
+            Some(ref target) => {
+                let name = settings.debug_bin_path.file_name().expect("the multicall link has a name");
+                body.push_str(&format!("mkdir -p \"$here/bin\" && ln -sf {} \"$here/bin\"/{} || exit\n",
+                                       shell_word(target, tmpdir), shell_word(name, tmpdir)));
+                Some(format!("\"$here/bin\"/{}", shell_word(name, tmpdir)))
+            },
+            None => None,
+        };
+        let word = |value: &OsStr| match relinked {
+            Some(ref relinked) if value == settings.debug_bin_path.as_os_str() => relinked.clone(),
+            _ => shell_word(value, tmpdir),
+        };
+        body.push_str(&format!("cd {} || exit\n", shell_word(&self.curdir, tmpdir)));
+
+        let assignment = |key: &OsStr, val: &OsStr| {
+            let mut pair = OsString::from(key);
+            pair.push("=");
+            pair.push(val);
+            shell_word(pair, tmpdir)
+        };
+        let mut line = String::from("exec env");
+        if self.env_cleared {
+            line.push_str(" -i");
+            for (key, val) in self.effective_env() {
+                line.push_str(&format!(" {}", assignment(&key, &val)));
+            }
+        } else {
+            let mut changes = BTreeMap::new();
+            for (key, val) in self.env_changes.iter() {
+                changes.insert(key, val);
+            }
+            // env only accepts removals before assignments
+            for (key, _) in changes.iter().filter(|&(_, val)| val.is_none()) {
+                line.push_str(&format!(" -u {}", shell_word(key, tmpdir)));
+            }
+            for (key, val) in changes.iter() {
+                if let Some(ref val) = **val {
+                    line.push_str(&format!(" {}", assignment(key, val)));
+                }
+            }
+        }
+        line.push_str(&format!(" {}", word(self.raw.get_program())));
+        for arg in self.raw.get_args() {
+            line.push_str(&format!(" {}", word(arg)));
+        }
+
+        if !fed_by_pipe {
+            line.push_str(&match self.stdin_source {
+                StdinSource::Pipe if self.stdin.is_some() => String::from(" < \"$here/stdin\""),
+                StdinSource::Pipe | StdinSource::Null => String::from(" < /dev/null"),
+                StdinSource::Closed => String::from(" <&-"),
+                StdinSource::File(ref path) => format!(" < {}", shell_word(path, tmpdir)),
+            });
+        }
+        if let Some(ref path) = self.stdout_file {
+            line.push_str(&format!(" > {}", shell_word(path, tmpdir)));
+        }
+        if let Some(ref path) = self.stderr_file {
+            line.push_str(&format!(" 2> {}", shell_word(path, tmpdir)));
+        }
+        for &(n, ref source) in self.extra_fds.iter() {
+            line.push_str(&match *source {
+                FdSource::Capture => format!(" {}> \"$here/fd{}\"", n, n),
+                FdSource::ReadFile(ref path) => format!(" {}< {}", n, shell_word(path, tmpdir)),
+                FdSource::WriteFile(ref path) => format!(" {}> {}", n, shell_word(path, tmpdir)),
+            });
+        }
+        body.push_str(&line);
+        body.push('\n');
+        body
+    }
+
+    fn spawn(&mut self) -> Child {
         if self.has_run {
             panic!(ALREADY_RUN);
         }
         self.has_run = true;
-        log_info("run", &self.comm_string);
-        let mut result = self.raw
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
+        // a command of a pipeline is already given a script reproducing the whole pipeline
+        if self.repro.is_none() {
+            self.repro = Some(Arc::new(self.repro()));
+        }
+        log_info("run", format!("{} (cwd: {})", self.comm_string, self.cwd_string()));
+        log_info("env", self.env_string());
+        // in pty mode, the command is already made the leader of a new session
+        if (self.timeout.is_some() || self.leftovers != Leftovers::Ignore) && self.pty.is_none() {
+            own_process_group(&mut self.raw);
+        }
+        if !self.resource_limits.is_empty() {
+            set_resource_limits(&mut self.raw, &self.resource_limits);
+        }
+        let fd_handles = if self.extra_fds.is_empty() { Vec::new() } else { self.open_extra_fds() };
+        let child = self.raw
             .spawn()
             .expect("There was an error running the provided command. Run cargo test with --verbose to see which command caused the failure");
+        // close this process's handles, so that captured pipes reach an end once the command exits
+        drop(fd_handles);
+        // likewise for files or pipes the output streams were redirected to
+        if self.stdout_file.is_some() || self.stderr_file.is_some() {
+            self.raw.stdout(Stdio::null()).stderr(Stdio::null());
+        }
+        child
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    // spawns the command, and starts capturing its output
+    fn start(&mut self) -> Running {
+        let deadline = self.deadline();
+        if let Some((cols, rows)) = self.pty {
+            if self.stdout_read_limit.is_some() {
+                panic!("a stdout read limit is not supported in pty mode");
+            }
+            if self.stdin_source != StdinSource::Pipe {
+                panic!("in pty mode, stdin is always the terminal");
+            }
+            if self.stdout_file.is_some() || self.stderr_file.is_some() {
+                panic!("in pty mode, stdout and stderr are always the terminal");
+            }
+            let pty = Pty::open(cols, rows);
+            pty.attach(&mut self.raw);
+            let child = self.spawn();
+            // drop the command's handles to the slave side, otherwise reads
+            // from the master side would never reach an end once the command exits
+            self.raw.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
+            let mut running = Running::new_pty(child, pty.master, self.capture_combined, deadline, self.settings.clone(), self.comm_string.clone());
+            running.repro = self.repro.clone();
+            running.leftovers = self.leftovers;
+            running
+        } else {
+            let stdin = self.stdin_stdio();
+            self.raw
+                .stdin(stdin)
+                .stdout(UCommand::output_stdio(&self.stdout_file))
+                .stderr(UCommand::output_stdio(&self.stderr_file));
+            let child = self.spawn();
+            let mut running = Running::new(child, self.stdout_read_limit, self.capture_combined, deadline, self.settings.clone(), self.comm_string.clone());
+            running.fds = self.fd_captures.drain(..).collect();
+            running.repro = self.repro.clone();
+            running.leftovers = self.leftovers;
+            running
+        }
+    }
 
-        if let Some(ref input) = self.stdin {
-            result.stdin
-                .take()
-                .unwrap_or_else(
-                    || panic!(
-                        "Could not take child process stdin"))
-                .write_all(&input)
-                .unwrap_or_else(|e| panic!("{}", e));
+    // spawns the command as a stage of a pipeline. Its stdin is read from the
+    // previous stage's stdout if provided, or otherwise fed from pipe_in(...).
+    // Its stdout is returned for piping to the next stage, unless it's the last stage.
+    pub(crate) fn start_stage(&mut self, previous_stdout: Option<ChildStdout>, last: bool) -> (Running, Option<ChildStdout>) {
+        if self.pty.is_some() {
+            panic!("pty mode is not supported for commands in a pipeline");
+        }
+        let fed_by_previous = previous_stdout.is_some();
+        if fed_by_previous && (self.stdin.is_some() || self.stdin_source != StdinSource::Pipe) {
+            panic!("only the first command of a pipeline can be provided stdin");
+        }
+        let stdin = match previous_stdout {
+            Some(previous_stdout) => Stdio::from(previous_stdout),
+            None => self.stdin_stdio(),
+        };
+        if !last && self.stdout_file.is_some() {
+            panic!("only the last command of a pipeline can have its stdout redirected to a file");
+        }
+        self.raw
+            .stdin(stdin)
+            .stdout(UCommand::output_stdio(&self.stdout_file))
+            .stderr(UCommand::output_stdio(&self.stderr_file));
+        let mut child = self.spawn();
+        // drop this process's handle to the previous stage's stdout, so that only
+        // this stage reads from it, and the previous stage gets SIGPIPE once it exits
+        self.raw.stdin(Stdio::null());
+        let next_stdin = if last { None } else { child.stdout.take() };
+        let mut running = Running::new(child, self.stdout_read_limit, self.capture_combined, self.deadline(), self.settings.clone(), self.comm_string.clone());
+        running.fds = self.fd_captures.drain(..).collect();
+        running.repro = self.repro.clone();
+        running.leftovers = self.leftovers;
+        if let Some(input) = self.stdin.take() {
+            running.feed(input);
+        }
+        (running, next_stdin)
+    }
+
+    // spawns the command as a long-running service, feeding it any stdin provided.
+    // anything left in its process group once it exits is killed, so that
+    // stopping the service also stops any helpers it spawned
+    pub(crate) fn start_service(&mut self) -> Running {
+        if self.leftovers == Leftovers::Ignore {
+            self.leftovers = Leftovers::Kill;
+        }
+        let mut running = self.start();
+        if let Some(input) = self.stdin.take() {
+            running.feed(input);
+        }
+        running
+    }
+
+    pub(crate) fn set_repro(&mut self, repro: Arc<Repro>) {
+        self.repro = Some(repro);
+    }
+
+    pub(crate) fn stdin_bytes(&self) -> Option<&Vec<u8>> {
+        self.stdin.as_ref()
+    }
+
+    pub(crate) fn settings(&self) -> &Arc<SceneSettings> {
+        &self.settings
+    }
+
+    pub(crate) fn comm_string(&self) -> &str {
+        &self.comm_string
+    }
+
+    /// Spawns the command, starts feeding the stdin if any on a separate thread,
+    /// and returns the child process immediately.
+    /// to interact with, or send signals to, the running command and then
+    /// get a command result, use run_interactive() instead.
+    pub fn run_no_wait(&mut self) -> Child {
+        if self.pty.is_some() {
+            panic!("run_no_wait() is not supported in pty mode, use run_interactive() instead");
+        }
+        let stdin = self.stdin_stdio();
+        self.raw
+            .stdin(stdin)
+            .stdout(UCommand::output_stdio(&self.stdout_file))
+            .stderr(UCommand::output_stdio(&self.stderr_file));
+        let mut result = self.spawn();
+
+        if let Some(input) = self.stdin.take() {
+            feed(result.stdin
+                     .take()
+                     .unwrap_or_else(
+                         || panic!(
+                             "Could not take child process stdin")),
+                 input);
         }
 
         result
@@ -154,14 +879,22 @@ impl UCommand {
     /// and returns a command result.
     /// It is recommended that you instead use succeeds() or fails()
     pub fn run(&mut self) -> CmdResult {
-        let prog = self.run_no_wait().wait_with_output().unwrap();
+        let mut running = self.start();
+        if let Some(input) = self.stdin.take() {
+            running.feed(input);
+        }
+        running.finish()
+    }
 
-        CmdResult {
-            settings: self.settings.clone(),
-            success: prog.status.success(),
-            stdout: from_utf8(&prog.stdout).unwrap().to_string(),
-            stderr: from_utf8(&prog.stderr).unwrap().to_string(),
+    /// Spawns the command and returns a session for exchanging input and output
+    /// with it, and sending it signals, while it runs.
+    /// Stdin provided by pipe_in(...), if any, is sent first.
+    pub fn run_interactive(&mut self) -> InteractiveSession {
+        let mut session = InteractiveSession::new(self.start());
+        if let Some(input) = self.stdin.take() {
+            session.send(input);
         }
+        session
     }
 
     /// Spawns the command, feeding the passed in stdin, waits for the result